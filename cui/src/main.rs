@@ -1,14 +1,67 @@
+use std::fmt;
+use std::path::Path;
 use std::time::Duration;
 
-use engine;
 use tokio::io::{stdin, AsyncBufReadExt, BufReader};
 use tokio::sync::mpsc::error::SendError;
 use tokio::sync::mpsc::{self, UnboundedSender};
 use vampirc_uci::{parse_one, UciMessage};
 
+/// Command line flags.
+#[derive(Default)]
+struct Args {
+    /// Evaluation parameter file loaded at startup, like the UCI option `EvalParams`.
+    eval_params: Option<String>,
+    /// Write the default evaluation parameters to this file and exit.
+    save_eval_params: Option<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args::default();
+    let mut flags = std::env::args().skip(1);
+    while let Some(flag) = flags.next() {
+        let target = match flag.as_str() {
+            "--eval-params" => &mut args.eval_params,
+            "--save-eval-params" => &mut args.save_eval_params,
+            _ => return Err(format!("unknown flag {}", flag)),
+        };
+        match flags.next() {
+            Some(path) => *target = Some(path),
+            None => return Err(format!("missing file for {}", flag)),
+        }
+    }
+    Ok(args)
+}
+
 #[tokio::main]
 async fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            println!(
+                "Usage: cui [--eval-params FILE] [--save-eval-params FILE] - {}",
+                e
+            );
+            return;
+        }
+    };
+    if let Some(path) = args.save_eval_params {
+        match engine::EvalParams::default().save(Path::new(&path)) {
+            Ok(()) => println!("Saved evaluation parameters to {}", path),
+            Err(e) => println!("Saving evaluation parameters failed: {}", e),
+        }
+        return;
+    }
+
     let (broker_command_sender, broker_command_reciever) = mpsc::unbounded_channel();
+    if let Some(path) = args.eval_params {
+        broker_command_sender
+            .send(engine::EngineCommand::SetOption {
+                name: "EvalParams".to_string(),
+                value: Some(path),
+            })
+            .unwrap();
+    }
     let (broker_output_sender, mut broker_output_reciever) = mpsc::unbounded_channel();
     let _broker_handle = tokio::spawn(engine::broker_loop(
         broker_command_reciever,
@@ -17,7 +70,10 @@ async fn main() {
     tokio::spawn(async move {
         println!("info string OutputTask started");
         while let Some(msg) = broker_output_reciever.recv().await {
-            println!("{}", msg);
+            match msg {
+                UciMessage::Unknown(line, None) => println!("{}", line),
+                msg => println!("{}", msg),
+            }
         }
         println!("info string OutputTask shutdown");
     });
@@ -38,17 +94,22 @@ async fn spawn_uci(
     let mut lines = reader.lines();
 
     while let Ok(line) = lines.next_line().await {
-        let msg = parse_one(&line.unwrap());
+        let line = line.unwrap();
+        let msg = parse_one(&line);
+        let ponder = is_go_ponder(&line);
         match msg {
             UciMessage::Quit => {
                 break;
             }
             _ => {
-                tokio::spawn(handle_message(
-                    msg,
-                    engine_command_sender.clone(),
-                    output.clone(),
-                ));
+                let engine_command_sender = engine_command_sender.clone();
+                let output = output.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_message(msg, ponder, engine_command_sender, output).await
+                    {
+                        println!("info string HandleMessage failed: {}", e);
+                    }
+                });
             }
         }
         tokio::time::sleep(Duration::from_millis(10)).await;
@@ -56,13 +117,30 @@ async fn spawn_uci(
     println!("info string UCITask shutdown");
 }
 
+/// `go ponder` followed by clock times is parsed as a plain `go` with the clock as time
+/// control, so the ponder flag is taken from the raw command.
+fn is_go_ponder(line: &str) -> bool {
+    let mut tokens = line.split_whitespace();
+    tokens.next() == Some("go") && tokens.any(|token| token == "ponder")
+}
+
 enum HandleMessageError {
     Engine(SendError<engine::EngineCommand>),
     Output(SendError<UciMessage>),
 }
 
+impl fmt::Display for HandleMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandleMessageError::Engine(e) => write!(f, "engine channel closed - {}", e),
+            HandleMessageError::Output(e) => write!(f, "output channel closed - {}", e),
+        }
+    }
+}
+
 async fn handle_message(
     msg: UciMessage,
+    ponder: bool,
     engine_command_sender: UnboundedSender<engine::EngineCommand>,
     output: UnboundedSender<UciMessage>,
 ) -> Result<(), HandleMessageError> {
@@ -74,40 +152,42 @@ async fn handle_message(
                     name: Some("WolfChess".to_string()),
                     author: None,
                 })
-                .map_err(|e| HandleMessageError::Output(e))?;
+                .map_err(HandleMessageError::Output)?;
             output
                 .send(UciMessage::Id {
                     name: None,
                     author: Some("Jan Niklas Richter".to_string()),
                 })
-                .map_err(|e| HandleMessageError::Output(e))?;
+                .map_err(HandleMessageError::Output)?;
+            for option in engine::options() {
+                output
+                    .send(UciMessage::Option(option))
+                    .map_err(HandleMessageError::Output)?;
+            }
             output
                 .send(UciMessage::UciOk)
-                .map_err(|e| HandleMessageError::Output(e))?;
+                .map_err(HandleMessageError::Output)?;
         }
         UciMessage::IsReady => {
             let command = engine::EngineCommand::IsReady;
             engine_command_sender
                 .send(command)
-                .map_err(|e| HandleMessageError::Engine(e))?;
+                .map_err(HandleMessageError::Engine)?;
         }
         UciMessage::Position {
             startpos,
             fen,
             moves,
         } => {
-            let fen_str = match fen {
-                Some(ucifen) => Some(ucifen.to_string()),
-                None => None,
-            };
+            let fen_str = fen.map(|ucifen| ucifen.to_string());
             let command = engine::EngineCommand::SetPosition {
-                startpos: startpos,
+                startpos,
                 fen: fen_str,
-                moves: moves,
+                moves,
             };
             engine_command_sender
                 .send(command)
-                .map_err(|e| HandleMessageError::Engine(e))?;
+                .map_err(HandleMessageError::Engine)?;
         }
         UciMessage::Go {
             time_control,
@@ -116,35 +196,66 @@ async fn handle_message(
             let command = engine::EngineCommand::Search {
                 time_control,
                 search_control,
+                ponder,
             };
             engine_command_sender
                 .send(command)
-                .map_err(|e| HandleMessageError::Engine(e))?;
+                .map_err(HandleMessageError::Engine)?;
         }
         UciMessage::Stop => {
             let command = engine::EngineCommand::StopSearch;
             engine_command_sender
                 .send(command)
-                .map_err(|e| HandleMessageError::Engine(e))?;
+                .map_err(HandleMessageError::Engine)?;
+        }
+        UciMessage::UciNewGame => {
+            let command = engine::EngineCommand::NewGame;
+            engine_command_sender
+                .send(command)
+                .map_err(HandleMessageError::Engine)?;
+        }
+        UciMessage::PonderHit => {
+            let command = engine::EngineCommand::PonderHit;
+            engine_command_sender
+                .send(command)
+                .map_err(HandleMessageError::Engine)?;
+        }
+        UciMessage::SetOption { name, value } => {
+            let command = engine::EngineCommand::SetOption { name, value };
+            engine_command_sender
+                .send(command)
+                .map_err(HandleMessageError::Engine)?;
         }
         UciMessage::Unknown(message_str, err) => match message_str.as_str() {
             "perft" => {
                 let command = engine::EngineCommand::Perft { depth: 7 };
                 engine_command_sender
                     .send(command)
-                    .map_err(|e| HandleMessageError::Engine(e))?;
+                    .map_err(HandleMessageError::Engine)?;
+            }
+            "bench" => {
+                let command = engine::EngineCommand::Bench { depth: 6 };
+                engine_command_sender
+                    .send(command)
+                    .map_err(HandleMessageError::Engine)?;
+            }
+            "tactics" => {
+                let command = engine::EngineCommand::Tactics { nodes: 1_000_000 };
+                engine_command_sender
+                    .send(command)
+                    .map_err(HandleMessageError::Engine)?;
             }
             "eval" => {
                 let command = engine::EngineCommand::EvalCurrentPosition;
                 engine_command_sender
                     .send(command)
-                    .map_err(|e| HandleMessageError::Engine(e))?;
+                    .map_err(HandleMessageError::Engine)?;
             }
             "show" => {
                 let command = engine::EngineCommand::ShowBoard;
                 engine_command_sender
                     .send(command)
-                    .map_err(|e| HandleMessageError::Engine(e))?;
+                    .map_err(HandleMessageError::Engine)?;
             }
             _ => {
                 output
@@ -152,7 +263,7 @@ async fn handle_message(
                         "Unknown message - {:#?} {}",
                         err, message_str
                     )))
-                    .map_err(|e| HandleMessageError::Output(e))?;
+                    .map_err(HandleMessageError::Output)?;
             }
         },
         _ => {
@@ -161,7 +272,7 @@ async fn handle_message(
                     "Message not yet implemented - {}",
                     msg
                 )))
-                .map_err(|e| HandleMessageError::Output(e))?;
+                .map_err(HandleMessageError::Output)?;
         }
     }
     println!("info string HandleMessage shutdown");
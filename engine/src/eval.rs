@@ -1,73 +1,269 @@
-use chess::{Board, Color, MoveGen, Piece, Square, ALL_PIECES};
+use std::fmt;
 
-use crate::weights;
+use chess::{Board, Color, Piece, Square, ALL_PIECES};
 
-pub const MAX_CP_SCORE: i32 = 1000000;
+use crate::{
+    king_safety,
+    params::EvalParams,
+    pawns::{self, PawnHashTable},
+    pieces, search,
+    weights::Weight,
+};
 
-pub fn evaluate_position(board: &Board) -> i32 {
-    let result_white = evaluate_material_for_color(board, Color::White)
-        + evaluate_modifiers_for_color(board, Color::White);
-    let result_black = evaluate_material_for_color(board, Color::Black)
-        + evaluate_modifiers_for_color(board, Color::Black);
+/// Bound of all scores, it has to fit into the `i16` of a transposition table entry.
+pub const MAX_CP_SCORE: i32 = 32000;
 
-    match board.side_to_move() {
-        Color::White => result_white - result_black,
-        Color::Black => result_black - result_white,
+/// Bound of the static evaluation, a larger score would be read as a mate score.
+const MAX_EVAL_SCORE: i32 = search::MATE_THRESHOLD - 1;
+
+/// Game phase of the starting position, the phase drops to 0 as pieces are traded.
+const MAX_GAMEPHASE: i32 = 24;
+
+/// A term of the evaluation, see `EvalTrace`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EvalTerm {
+    Material,
+    PieceSquares,
+    Pairs,
+    Mobility,
+    Pieces,
+    KingSafety,
+    PawnStructure,
+    Tempo,
+}
+
+impl EvalTerm {
+    pub const ALL: [EvalTerm; 8] = [
+        EvalTerm::Material,
+        EvalTerm::PieceSquares,
+        EvalTerm::Pairs,
+        EvalTerm::Mobility,
+        EvalTerm::Pieces,
+        EvalTerm::KingSafety,
+        EvalTerm::PawnStructure,
+        EvalTerm::Tempo,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EvalTerm::Material => "Material",
+            EvalTerm::PieceSquares => "Piece squares",
+            EvalTerm::Pairs => "Pairs",
+            EvalTerm::Mobility => "Mobility",
+            EvalTerm::Pieces => "Pieces",
+            EvalTerm::KingSafety => "King safety",
+            EvalTerm::PawnStructure => "Pawn structure",
+            EvalTerm::Tempo => "Tempo",
+        }
+    }
+}
+
+/// Breakdown of the evaluation of a position into its terms.
+#[derive(Clone, Debug)]
+pub struct EvalTrace {
+    /// Score of each term by color, indexed by `EvalTerm` and `Color::to_index`.
+    pub terms: [[Weight; 2]; EvalTerm::ALL.len()],
+    /// Game phase from 0 (endgame) to `MAX_GAMEPHASE` (midgame).
+    pub gamephase: i32,
+    /// Tapered score from the view of the side to move, as `evaluate_position`.
+    pub score: i32,
+}
+
+impl EvalTrace {
+    pub fn term(&self, term: EvalTerm, color: Color) -> Weight {
+        self.terms[term as usize][color.to_index()]
+    }
+
+    /// Sum of all terms from the view of white.
+    pub fn total(&self) -> Weight {
+        let mut total = Weight::default();
+        for [white, black] in self.terms {
+            total += white - black;
+        }
+        total
+    }
+}
+
+impl fmt::Display for EvalTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<15}|{:^15}|{:^15}|{:^15}",
+            "Term", "White", "Black", "Total"
+        )?;
+        writeln!(
+            f,
+            "{:<15}|{:>7}{:>7} |{:>7}{:>7} |{:>7}{:>7} ",
+            "", "MG", "EG", "MG", "EG", "MG", "EG"
+        )?;
+        writeln!(f, "{0:-<15}+{0:-<15}+{0:-<15}+{0:-<15}", "")?;
+        for term in EvalTerm::ALL {
+            let white = self.term(term, Color::White);
+            let black = self.term(term, Color::Black);
+            let total = white - black;
+            writeln!(
+                f,
+                "{:<15}|{:>7}{:>7} |{:>7}{:>7} |{:>7}{:>7} ",
+                term.name(),
+                white.mg,
+                white.eg,
+                black.mg,
+                black.eg,
+                total.mg,
+                total.eg
+            )?;
+        }
+        writeln!(f, "{0:-<15}+{0:-<15}+{0:-<15}+{0:-<15}", "")?;
+        let total = self.total();
+        writeln!(
+            f,
+            "{:<15}|{:>15}|{:>15}|{:>7}{:>7} ",
+            "Total", "", "", total.mg, total.eg
+        )?;
+        write!(
+            f,
+            "Phase {}/{}, score {} cp from the view of the side to move",
+            self.gamephase, MAX_GAMEPHASE, self.score
+        )
+    }
+}
+
+pub fn evaluate_position(
+    board: &Board,
+    params: &EvalParams,
+    pawn_table: &mut PawnHashTable,
+) -> i32 {
+    let mut result = Weight::default();
+    evaluate_terms(board, params, pawn_table, |_, color, weight| match color {
+        Color::White => result += weight,
+        Color::Black => result += -weight,
+    });
+    score_for_side_to_move(board, result)
+}
+
+/// Evaluate the position like `evaluate_position` and report every term.
+pub fn trace_position(board: &Board, params: &EvalParams) -> EvalTrace {
+    let mut terms = [[Weight::default(); 2]; EvalTerm::ALL.len()];
+    evaluate_terms(
+        board,
+        params,
+        &mut PawnHashTable::new(),
+        |term, color, weight| terms[term as usize][color.to_index()] += weight,
+    );
+    let mut trace = EvalTrace {
+        terms,
+        gamephase: get_gamephase(board).min(MAX_GAMEPHASE),
+        score: 0,
+    };
+    trace.score = score_for_side_to_move(board, trace.total());
+    trace
+}
+
+/// Pass the score of every term of both colors to `add`.
+fn evaluate_terms(
+    board: &Board,
+    params: &EvalParams,
+    pawn_table: &mut PawnHashTable,
+    mut add: impl FnMut(EvalTerm, Color, Weight),
+) {
+    let pawn_structure = pawns::evaluate_pawn_structure(board, params, pawn_table);
+    for color in [Color::White, Color::Black] {
+        let pieces = pieces::evaluate_pieces(board, params, color);
+        add(
+            EvalTerm::Material,
+            color,
+            evaluate_material_for_color(board, params, color),
+        );
+        add(
+            EvalTerm::PieceSquares,
+            color,
+            evaluate_pst_for_color(board, params, color),
+        );
+        add(
+            EvalTerm::Pairs,
+            color,
+            evaluate_pair_modifier_for_color(board, params, color),
+        );
+        add(EvalTerm::Mobility, color, pieces.mobility);
+        add(EvalTerm::Pieces, color, pieces.placement);
+        add(
+            EvalTerm::KingSafety,
+            color,
+            king_safety::evaluate_king_safety(board, params, color),
+        );
+        add(
+            EvalTerm::PawnStructure,
+            color,
+            pawn_structure[color.to_index()],
+        );
+        add(
+            EvalTerm::Tempo,
+            color,
+            evaluate_tempo_modifier_for_color(board, params, color),
+        );
     }
 }
 
-fn evaluate_material_for_color(board: &Board, color: Color) -> i32 {
-    let mut material_value: i32 = 0;
-    let gamephase = get_gamephase(board);
+/// Taper a score from the view of white and turn it to the view of the side to move.
+fn score_for_side_to_move(board: &Board, result: Weight) -> i32 {
+    let result = match board.side_to_move() {
+        Color::White => result,
+        Color::Black => -result,
+    };
+    taper(result, get_gamephase(board)).clamp(-MAX_EVAL_SCORE, MAX_EVAL_SCORE)
+}
+
+/// Blend the midgame and endgame value of a weight by the game phase.
+fn taper(weight: Weight, gamephase: i32) -> i32 {
+    let gamephase = gamephase.min(MAX_GAMEPHASE);
+    (weight.mg * gamephase + weight.eg * (MAX_GAMEPHASE - gamephase)) / MAX_GAMEPHASE
+}
+
+fn evaluate_material_for_color(board: &Board, params: &EvalParams, color: Color) -> Weight {
+    let mut material_value = Weight::default();
 
     for piece_type in ALL_PIECES {
         let pieces = board.pieces(piece_type) & board.color_combined(color);
-        material_value += (pieces.popcnt() as i32) * get_piece_type_material_value(piece_type);
-        material_value += get_pst_value_for_piece_type(color, piece_type, pieces, gamephase);
+        material_value +=
+            get_piece_type_material_value(params, piece_type) * pieces.popcnt() as i32;
     }
 
     material_value
 }
 
-fn evaluate_modifiers_for_color(board: &Board, color: Color) -> i32 {
-    return evaluate_pair_modifier_for_color(board, color)
-        + evaluate_mobility_modifier_for_color(board, color)
-        + evaluate_tempo_modifier_for_color(board, color);
+fn evaluate_pst_for_color(board: &Board, params: &EvalParams, color: Color) -> Weight {
+    let mut pst_value = Weight::default();
+
+    for piece_type in ALL_PIECES {
+        let pieces = board.pieces(piece_type) & board.color_combined(color);
+        pst_value += get_pst_value_for_piece_type(params, color, piece_type, pieces);
+    }
+
+    pst_value
 }
 
-fn evaluate_pair_modifier_for_color(board: &Board, color: Color) -> i32 {
-    let mut score = 0;
+fn evaluate_pair_modifier_for_color(board: &Board, params: &EvalParams, color: Color) -> Weight {
+    let mut score = Weight::default();
 
     if (board.pieces(Piece::Bishop) & board.color_combined(color)).popcnt() >= 2 {
-        score += weights::PAIR_MOD_BISHOP;
+        score += params.pair_mod_bishop;
     }
     if (board.pieces(Piece::Knight) & board.color_combined(color)).popcnt() >= 2 {
-        score += weights::PAIR_MOD_KNIGHT;
+        score += params.pair_mod_knight;
     }
     if (board.pieces(Piece::Rook) & board.color_combined(color)).popcnt() >= 2 {
-        score += weights::PAIR_MOD_ROOK;
+        score += params.pair_mod_rook;
     }
 
-    return score;
-}
-
-fn evaluate_mobility_modifier_for_color(board: &Board, color: Color) -> i32 {
-    let adj_board = if board.side_to_move() == color {
-        Some(*board)
-    } else {
-        board.null_move()
-    };
-
-    let mobility = adj_board.map_or(0, |b| MoveGen::new_legal(&b).len());
-    return mobility as i32 * weights::MOBILITY_MOD;
+    score
 }
 
-fn evaluate_tempo_modifier_for_color(board: &Board, color: Color) -> i32 {
-    return if board.side_to_move() == color {
-        weights::TEMPO_MOD
+fn evaluate_tempo_modifier_for_color(board: &Board, params: &EvalParams, color: Color) -> Weight {
+    if board.side_to_move() == color {
+        params.tempo_mod
     } else {
-        0
-    };
+        Weight::default()
+    }
 }
 
 fn get_gamephase(board: &Board) -> i32 {
@@ -77,50 +273,69 @@ fn get_gamephase(board: &Board) -> i32 {
         + 4 * board.pieces(Piece::Queen).popcnt()) as i32
 }
 
-fn get_piece_type_material_value(piece_type: Piece) -> i32 {
+pub fn get_piece_type_material_value(params: &EvalParams, piece_type: Piece) -> Weight {
     match piece_type {
-        Piece::King => weights::KING_MV,
-        Piece::Queen => weights::QUEEN_MV,
-        Piece::Rook => weights::ROOK_MV,
-        Piece::Bishop => weights::BISHOP_MV,
-        Piece::Knight => weights::KNIGHT_MV,
-        Piece::Pawn => weights::PAWN_MV,
+        Piece::King => params.king_mv,
+        Piece::Queen => params.queen_mv,
+        Piece::Rook => params.rook_mv,
+        Piece::Bishop => params.bishop_mv,
+        Piece::Knight => params.knight_mv,
+        Piece::Pawn => params.pawn_mv,
     }
 }
 
 fn get_pst_value_for_piece_type(
+    params: &EvalParams,
     color: Color,
     piece_type: Piece,
     pieces: chess::BitBoard,
-    gamephase: i32,
-) -> i32 {
-    let mut material_value = 0;
+) -> Weight {
+    let mut material_value = Weight::default();
     for square in pieces {
-        material_value += get_pst_value_for_square(color, piece_type, square, gamephase);
+        material_value += get_pst_value_for_square(params, color, piece_type, square);
     }
     material_value
 }
 
 fn get_pst_value_for_square(
+    params: &EvalParams,
     color: Color,
     piece_type: Piece,
     square: Square,
-    gamephase: i32,
-) -> i32 {
+) -> Weight {
     let square_adj = match color {
         Color::White => square.to_index(),
         Color::Black => square.to_index() ^ 56,
     };
-    match piece_type {
-        Piece::Pawn => *weights::PAWN_PST.get(square_adj).unwrap_or(&0),
-        Piece::Knight => *weights::KNIGHT_PST.get(square_adj).unwrap_or(&0),
-        Piece::Bishop => *weights::BISHOP_PST.get(square_adj).unwrap_or(&0),
-        Piece::Rook => *weights::ROOK_PST.get(square_adj).unwrap_or(&0),
-        Piece::Queen => *weights::QUEEN_PST.get(square_adj).unwrap_or(&0),
-        Piece::King => {
-            (gamephase * *weights::KING_PST.get(square_adj).unwrap_or(&0)
-                + (24 - gamephase) * *weights::KING_PST_EG.get(square_adj).unwrap_or(&0))
-                / 24
-        }
+    let pst = match piece_type {
+        Piece::Pawn => &params.pawn_pst,
+        Piece::Knight => &params.knight_pst,
+        Piece::Bishop => &params.bishop_pst,
+        Piece::Rook => &params.rook_pst,
+        Piece::Queen => &params.queen_pst,
+        Piece::King => &params.king_pst,
+    };
+    pst[square_adj]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn evaluation_is_not_a_mate_score() {
+        // Parameters set directly are not checked against the bounds of `load`.
+        let params = EvalParams {
+            queen_mv: Weight::new(20000, 20000),
+            ..EvalParams::default()
+        };
+        let board = Board::from_str("7k/8/8/8/8/8/8/1QQQQ1K1 w - - 0 1").unwrap();
+        let score = evaluate_position(&board, &params, &mut PawnHashTable::new());
+        assert_eq!(score, MAX_EVAL_SCORE);
+        assert!(!search::is_mate_score(score));
+        let board = Board::from_str("7k/8/8/8/8/8/8/1QQQQ1K1 b - - 0 1").unwrap();
+        let score = evaluate_position(&board, &params, &mut PawnHashTable::new());
+        assert_eq!(score, -MAX_EVAL_SCORE);
     }
 }
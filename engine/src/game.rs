@@ -1,59 +1,240 @@
-use chess::{Board, ChessMove};
+use chess::{Board, ChessMove, Piece};
 use std::str::FromStr;
+use std::sync::Arc;
+
+/// A position hash with the history before it.
+///
+/// Games made from the same game share their history, so a move only allocates one
+/// entry instead of copying the whole history.
+#[derive(Debug)]
+struct HistoryEntry {
+    hash: u64,
+    previous: Option<Arc<HistoryEntry>>,
+}
 
 #[derive(Clone, Debug)]
 pub struct Game {
     current_position: Board,
-    moves: Vec<ChessMove>,
+    /// Hashes of all positions since the last irreversible move, newest first.
+    history: Option<Arc<HistoryEntry>>,
+    /// Halfmoves since the last capture or pawn move, for the fifty-move rule.
+    halfmove_clock: u32,
+    /// The side to move was passed by the search, see `make_null_move_new`.
+    after_null_move: bool,
 }
 
 impl Game {
     /// Create a new `Game` with the initial position.
     pub fn new() -> Game {
-        Game {
-            current_position: Board::default(),
-            moves: vec![],
-        }
+        Game::new_with_board(Board::default())
     }
 
     /// Create a new `Game` with a specific starting position.
     pub fn new_with_board(board: Board) -> Game {
         Game {
             current_position: board,
-            moves: vec![],
+            history: None,
+            halfmove_clock: 0,
+            after_null_move: false,
         }
     }
 
-    /// Get all actions made in this game (moves, draw offers, resignations, etc.)
-    pub fn moves(&self) -> &Vec<ChessMove> {
-        &self.moves
-    }
-
     /// Get position
     pub fn position(&self) -> &Board {
         &self.current_position
     }
 
     /// Create a new `Game` object from an FEN string.
+    #[allow(dead_code)]
     pub fn new_from_fen(fen: &str) -> Option<Game> {
         Game::from_str(fen).ok()
     }
 
     /// Get the current position on the board from the `Game` object.
     pub fn make_move_new(&self, mv: ChessMove) -> Game {
-        let copy = self.current_position.make_move_new(mv);
+        let position = &self.current_position;
+        let copy = position.make_move_new(mv);
+
+        let is_irreversible = position.piece_on(mv.get_source()) == Some(Piece::Pawn)
+            || position.piece_on(mv.get_dest()).is_some();
+
+        let (history, halfmove_clock) = if is_irreversible {
+            (None, 0)
+        } else {
+            let entry = HistoryEntry {
+                hash: position.get_hash(),
+                previous: self.history.clone(),
+            };
+            (Some(Arc::new(entry)), self.halfmove_clock + 1)
+        };
 
         Game {
             current_position: copy,
-            moves: self.moves.clone(),
+            history,
+            halfmove_clock,
+            after_null_move: false,
         }
     }
+
+    /// Pass the move to the opponent, which is not legal in chess but lets the search
+    /// prove that a position is good even without moving.
+    ///
+    /// Returns `None` if the side to move is in check. Positions before a null move do
+    /// not count for repetitions, they cannot be reached again with legal moves.
+    pub fn make_null_move_new(&self) -> Option<Game> {
+        Some(Game {
+            current_position: self.current_position.null_move()?,
+            history: None,
+            halfmove_clock: self.halfmove_clock + 1,
+            after_null_move: true,
+        })
+    }
+
+    /// Check if the last move was a null move.
+    pub fn is_after_null_move(&self) -> bool {
+        self.after_null_move
+    }
+
+    /// Check if the search can treat the current position as a draw by repetition,
+    /// `ply` moves after the root of the search.
+    ///
+    /// A single repetition inside the search tree is enough: if repeating is the best
+    /// option for both sides, it can be repeated again. Positions from before the root
+    /// were played in the game and need to occur three times, like a claimable draw.
+    pub fn is_repetition(&self, ply: usize) -> bool {
+        let hash = self.current_position.get_hash();
+        let mut earlier = 0;
+
+        // Only positions with the same side to move can be equal.
+        let distances = (1..).zip(self.history()).skip(1).step_by(2);
+        for (distance, _) in distances.filter(|&(_, h)| h == hash) {
+            earlier += 1;
+            if distance < ply || earlier == 2 {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Hashes of the positions since the last irreversible move, newest first.
+    fn history(&self) -> impl Iterator<Item = u64> + '_ {
+        let mut entry = self.history.as_deref();
+        std::iter::from_fn(move || {
+            let current = entry?;
+            entry = current.previous.as_deref();
+            Some(current.hash)
+        })
+    }
+
+    /// Check if the fifty-move rule allows claiming a draw.
+    ///
+    /// Checkmate on the hundredth halfmove takes precedence and must be checked
+    /// by the caller.
+    pub fn is_fifty_move_draw(&self) -> bool {
+        self.halfmove_clock >= 100
+    }
 }
 
 impl FromStr for Game {
     type Err = chess::Error;
 
     fn from_str(fen: &str) -> Result<Self, Self::Err> {
-        Ok(Game::new_with_board(Board::from_str(fen)?))
+        let mut game = Game::new_with_board(Board::from_str(fen)?);
+        // `Board` drops the halfmove clock, it is the fifth field of the FEN.
+        game.halfmove_clock = fen
+            .split_whitespace()
+            .nth(4)
+            .and_then(|clock| clock.parse().ok())
+            .unwrap_or(0);
+        Ok(game)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+    const KINGS: &str = "4k3/8/8/8/8/8/8/4K3 w - - 0 1";
+
+    fn play(fen: &str, moves: &[&str]) -> Game {
+        play_from(Game::from_str(fen).unwrap(), moves)
+    }
+
+    fn play_from(mut game: Game, moves: &[&str]) -> Game {
+        for mv in moves {
+            game = game.make_move_new(ChessMove::from_san(game.position(), mv).unwrap());
+        }
+        game
+    }
+
+    #[test]
+    fn knight_shuffle_repeats() {
+        let shuffle = ["Nf3", "Nf6", "Ng1", "Ng8"];
+        // The search starts at the start position, the position after Nf3 is the first
+        // one inside the search tree that repeats.
+        assert!(!play(START, &["Nf3", "Nf6", "Ng1"]).is_repetition(3));
+        assert!(play(START, &[&shuffle[..], &["Nf3"]].concat()).is_repetition(5));
+        // The root itself was played in the game.
+        assert!(!play(START, &shuffle).is_repetition(4));
+        assert!(play(START, &[shuffle, shuffle].concat()).is_repetition(8));
+    }
+
+    #[test]
+    fn positions_before_the_root_repeat_three_times() {
+        let shuffle = ["Nf3", "Nf6", "Ng1", "Ng8"];
+        // The start position occurs the second time in the game at the root.
+        let game = play(START, &shuffle);
+        assert!(!game.is_repetition(0));
+        // Coming back to it in the search makes it the third time.
+        assert!(play_from(game, &shuffle).is_repetition(4));
+
+        // The position after Nf3 once played in the game and once reached by the
+        // search, which started after Nf6.
+        let game = play(START, &[&shuffle[..], &["Nf3"]].concat());
+        assert!(!game.is_repetition(3));
+    }
+
+    #[test]
+    fn irreversible_move_clears_history() {
+        let start = Board::default().get_hash();
+        let game = play(START, &["Nf3", "Nf6", "Ng1", "Ng8", "e4"]);
+        assert!(!game.is_repetition(5));
+        assert_eq!(game.halfmove_clock, 0);
+
+        // Positions before the pawn move are gone, the ones after it count.
+        let game = play(
+            START,
+            &["Nf3", "Nf6", "Ng1", "Ng8", "e4", "Nf6", "Nf3", "Ng8", "Ng1"],
+        );
+        assert!(game.is_repetition(9));
+        assert!(game.history().all(|hash| hash != start));
+        assert_eq!(game.history().count(), 4);
+    }
+
+    #[test]
+    fn null_move_breaks_history() {
+        // The white king walks a triangle and is back with black to move, a null
+        // move would give the start position with white to move.
+        let game = play(KINGS, &["Kd1", "Kd8", "Kd2", "Ke8", "Ke1"]);
+        let game = game.make_null_move_new().unwrap();
+        assert_eq!(
+            game.position().get_hash(),
+            Board::from_str(KINGS).unwrap().get_hash()
+        );
+        assert!(game.is_after_null_move());
+        assert!(!game.is_repetition(6));
+
+        // Positions after the null move still count.
+        let game = play_from(game, &["Kd2", "Kd8", "Ke1", "Ke8"]);
+        assert!(game.is_repetition(10));
+    }
+
+    #[test]
+    fn fifty_move_rule() {
+        let game = play("4k3/8/8/8/8/8/4P3/4K3 w - - 99 80", &[]);
+        assert!(!game.is_fifty_move_draw());
+        assert!(play_from(game.clone(), &["Kd1"]).is_fifty_move_draw());
+        assert!(!play_from(game, &["e3"]).is_fifty_move_draw());
     }
 }
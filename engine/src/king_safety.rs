@@ -0,0 +1,185 @@
+use chess::{
+    get_adjacent_files, get_bishop_moves, get_file, get_king_moves, get_knight_moves,
+    get_pawn_attacks, get_rook_moves, BitBoard, Board, Color, Piece, Square, EMPTY,
+};
+
+use crate::{params::EvalParams, weights::Weight};
+
+/// Ranks in front of the king on which pawns count as shield or storm.
+const PAWN_SHELTER_RANKS: usize = 3;
+
+/// Safety of the king of `color`. The weights are zero in the endgame, so the term
+/// is blended out as pieces are traded.
+pub fn evaluate_king_safety(board: &Board, params: &EvalParams, color: Color) -> Weight {
+    let king = board.king_square(color);
+    evaluate_king_attacks(board, params, color, king)
+        + evaluate_pawn_shelter(board, params, color, king)
+}
+
+/// Squares around the king plus the squares in front of those, towards the enemy.
+fn king_zone(color: Color, king: Square) -> BitBoard {
+    let around = get_king_moves(king) | BitBoard::from_square(king);
+    match color {
+        Color::White => around | BitBoard(around.0 << 8),
+        Color::Black => around | BitBoard(around.0 >> 8),
+    }
+}
+
+/// Danger from enemy pieces attacking the king zone and from safe checks.
+///
+/// The attack units grow quadratically, a single attacker is hardly a danger while
+/// several coordinated attackers are.
+fn evaluate_king_attacks(board: &Board, params: &EvalParams, color: Color, king: Square) -> Weight {
+    let occupied = *board.combined();
+    let enemy = board.color_combined(!color);
+    let zone = king_zone(color, king);
+
+    // Checks from squares we do not defend and the enemy can move to.
+    let safe = !attacked_squares(board, color) & !enemy;
+    let knight_checks = get_knight_moves(king) & safe;
+    let bishop_checks = get_bishop_moves(king, occupied) & safe;
+    let rook_checks = get_rook_moves(king, occupied) & safe;
+
+    let mut attackers = 0;
+    let mut attack_units = 0;
+    for piece in [Piece::Knight, Piece::Bishop, Piece::Rook, Piece::Queen] {
+        for square in board.pieces(piece) & enemy {
+            let (attacks, checks) = match piece {
+                Piece::Knight => (get_knight_moves(square), knight_checks),
+                Piece::Bishop => (get_bishop_moves(square, occupied), bishop_checks),
+                Piece::Rook => (get_rook_moves(square, occupied), rook_checks),
+                _ => (
+                    get_bishop_moves(square, occupied) | get_rook_moves(square, occupied),
+                    bishop_checks | rook_checks,
+                ),
+            };
+
+            let zone_attacks = (attacks & zone).popcnt() as i32;
+            if zone_attacks > 0 {
+                attackers += 1;
+                attack_units += params.king_zone_attack[piece.to_index()] * zone_attacks;
+            }
+            if attacks & checks != EMPTY {
+                attack_units += params.king_safe_check[piece.to_index()];
+            }
+        }
+    }
+
+    if attackers < 2 {
+        return Weight::default();
+    }
+    params.king_attack_danger * (attack_units * attack_units / 64)
+}
+
+/// All squares attacked by the pieces of `color`, sliders stopped by any piece.
+fn attacked_squares(board: &Board, color: Color) -> BitBoard {
+    let occupied = *board.combined();
+    let own = board.color_combined(color);
+    let mut attacked = get_king_moves(board.king_square(color));
+    for square in board.pieces(Piece::Pawn) & own {
+        attacked |= get_pawn_attacks(square, color, !EMPTY);
+    }
+    for square in board.pieces(Piece::Knight) & own {
+        attacked |= get_knight_moves(square);
+    }
+    let diagonal = board.pieces(Piece::Bishop) | board.pieces(Piece::Queen);
+    for square in diagonal & own {
+        attacked |= get_bishop_moves(square, occupied);
+    }
+    let straight = board.pieces(Piece::Rook) | board.pieces(Piece::Queen);
+    for square in straight & own {
+        attacked |= get_rook_moves(square, occupied);
+    }
+    attacked
+}
+
+/// Pawn shield in front of the king, enemy pawns storming towards it and open files
+/// next to it.
+fn evaluate_pawn_shelter(board: &Board, params: &EvalParams, color: Color, king: Square) -> Weight {
+    let pawns = board.pieces(Piece::Pawn);
+    let own_pawns = pawns & board.color_combined(color);
+    let enemy_pawns = pawns & board.color_combined(!color);
+    let king_rank = king.get_rank().to_index();
+    let files = get_file(king.get_file()) | get_adjacent_files(king.get_file());
+    let mut score = Weight::default();
+
+    for distance in 1..=PAWN_SHELTER_RANKS {
+        let rank = match color {
+            Color::White => king_rank + distance,
+            Color::Black => king_rank.wrapping_sub(distance),
+        };
+        if rank > 7 {
+            break;
+        }
+        let rank = chess::get_rank(chess::Rank::from_index(rank));
+        let index = distance - 1;
+        score += params.king_pawn_shield[index] * (own_pawns & files & rank).popcnt() as i32;
+        score += params.king_pawn_storm[index] * (enemy_pawns & files & rank).popcnt() as i32;
+    }
+
+    for file in files_around(king) {
+        let file = get_file(file);
+        if pawns & file == EMPTY {
+            score += params.king_open_file;
+        } else if own_pawns & file == EMPTY {
+            score += params.king_semi_open_file;
+        }
+    }
+
+    score
+}
+
+/// The file of the king and the files next to it.
+fn files_around(king: Square) -> impl Iterator<Item = chess::File> {
+    let file = king.get_file().to_index();
+    (file.saturating_sub(1)..=(file + 1).min(7)).map(chess::File::from_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn attacked_squares_of_all_pieces() {
+        // Pawn f2, knight b1, bishop c1 behind pawn d2, rook h1 and king e1.
+        let board = Board::from_str("4k3/8/8/8/8/8/3P1P2/1NB1K2R w - - 0 1").unwrap();
+        let attacked = attacked_squares(&board, Color::White);
+        for square in [
+            Square::E3,
+            Square::G3,
+            Square::A3,
+            Square::C3,
+            Square::D2,
+            Square::H8,
+        ] {
+            assert!(
+                attacked & BitBoard::from_square(square) != EMPTY,
+                "{}",
+                square
+            );
+        }
+        // The bishop is blocked by the pawn on d2.
+        assert!(attacked & BitBoard::from_square(Square::F4) == EMPTY);
+    }
+
+    #[test]
+    fn checks_on_defended_squares_are_not_safe() {
+        let params = EvalParams {
+            king_zone_attack: [0; 6],
+            king_safe_check: [0, 8, 0, 0, 0, 0],
+            king_attack_danger: Weight::new(1, 0),
+            ..EvalParams::default()
+        };
+        let units = |fen: &str| {
+            let board = Board::from_str(fen).unwrap();
+            let king = board.king_square(Color::White);
+            evaluate_king_attacks(&board, &params, Color::White, king).mg
+        };
+        // The knight can check on f3, and the queen makes a second attacker.
+        let undefended = units("4k3/8/8/4n3/8/8/2q5/6K1 w - - 0 1");
+        // The bishop on b7 covers f3, a square no pawn defends.
+        let defended = units("4k3/1B6/8/4n3/8/8/2q5/6K1 w - - 0 1");
+        assert_eq!((undefended, defended), (1, 0));
+    }
+}
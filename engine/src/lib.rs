@@ -1,17 +1,34 @@
 mod eval;
 mod game;
+mod king_safety;
+mod move_picker;
+mod params;
+mod pawns;
+mod pieces;
 mod search;
+pub mod see;
 mod time_broker;
+mod transposition_table;
 mod weights;
 
-use chess::{Board, ChessMove, Error, MoveGen};
+use chess::{ChessMove, Error, MoveGen};
+pub use eval::{evaluate_position, trace_position, EvalTerm, EvalTrace};
 use game::Game;
+pub use params::{EvalParams, EvalParamsError};
+pub use pawns::PawnHashTable;
+use search::{SearchLimits, SearchOptions};
 use std::convert::TryInto;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, TryLockError};
 use std::time::Instant;
 use time_broker::TimeBroker;
 use tokio::sync::mpsc::{error::SendError, UnboundedReceiver, UnboundedSender};
-use vampirc_uci::{UciInfoAttribute, UciMessage, UciSearchControl, UciTimeControl};
+use transposition_table::TranspositionTable;
+use vampirc_uci::{
+    UciInfoAttribute, UciMessage, UciOptionConfig, UciSearchControl, UciTimeControl,
+};
+pub use weights::Weight;
 
 #[derive(Clone, Eq, PartialEq, Debug, Hash)]
 pub enum EngineCommand {
@@ -33,35 +50,133 @@ pub enum EngineCommand {
         /// The perft depth from the current position
         depth: usize,
     },
+    /// Search a fixed set of positions to the given depth and report the node count
+    Bench {
+        /// The search depth for every position
+        depth: usize,
+    },
+    /// Search the positions of the tactical test suite with a node limit and report how
+    /// many are solved
+    Tactics {
+        /// The node limit for every position
+        nodes: u64,
+    },
     /// Start a search for best move from the current position
     Search {
         time_control: Option<UciTimeControl>,
         search_control: Option<UciSearchControl>,
+        /// Search on the opponent's time, the clock starts with `PonderHit`.
+        ponder: bool,
     },
+    /// The opponent played the expected move, continue the ponder search as normal search
+    PonderHit,
     /// Stop search next possible
     StopSearch,
-    /// Print evaluation score for current position
+    /// Print the evaluation terms and score for current position
     EvalCurrentPosition,
     /// Print the current board
     ShowBoard,
     /// Return okay as soon as calculation is finished
     IsReady,
+    /// Set a UCI option
+    SetOption { name: String, value: Option<String> },
+    /// The next positions are from a different game
+    NewGame,
+}
+
+const MAX_THREADS: usize = 256;
+const MAX_MULTI_PV: usize = 256;
+
+const PRUNING_OPTIONS: [&str; 4] = [
+    "ReverseFutilityPruning",
+    "FutilityPruning",
+    "Razoring",
+    "ProbCut",
+];
+
+/// Value of a string option that is not set, by UCI convention.
+const EMPTY_OPTION_VALUE: &str = "<empty>";
+
+const BENCH_HASH_SIZE_MB: usize = 16;
+/// Positions of the `bench` command, a mix of opening, middlegame and endgame.
+const BENCH_POSITIONS: [&str; 8] = [
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+    "r1bqkb1r/pppp1ppp/2n2n2/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R w KQkq - 4 4",
+    "r2q1rk1/pp2bppp/2n1pn2/3p4/2PP4/2N1PN2/PP3PPP/R2QKB1R w KQ - 0 9",
+    "2r3k1/pp3ppp/2n1b3/3p4/3P4/2NB1N2/PP3PPP/2R3K1 w - - 0 20",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+    "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10",
+    "8/8/4k3/3p4/3P4/4K3/8/8 w - - 0 1",
+];
+
+/// Positions of the `tactics` command in EPD format, each with the best move `bm`.
+const TACTICS_POSITIONS: &str = include_str!("../tactics.epd");
+
+/// UCI options supported by the engine, announced after `uci`.
+pub fn options() -> Vec<UciOptionConfig> {
+    let mut options = vec![
+        UciOptionConfig::Check {
+            name: "Ponder".to_string(),
+            default: Some(false),
+        },
+        UciOptionConfig::Spin {
+            name: "Hash".to_string(),
+            default: Some(TranspositionTable::DEFAULT_SIZE_MB as i64),
+            min: Some(1),
+            max: Some(TranspositionTable::MAX_SIZE_MB as i64),
+        },
+        UciOptionConfig::Button {
+            name: "Clear Hash".to_string(),
+        },
+        UciOptionConfig::Spin {
+            name: "Threads".to_string(),
+            default: Some(1),
+            min: Some(1),
+            max: Some(MAX_THREADS as i64),
+        },
+        UciOptionConfig::Spin {
+            name: "MultiPV".to_string(),
+            default: Some(1),
+            min: Some(1),
+            max: Some(MAX_MULTI_PV as i64),
+        },
+        UciOptionConfig::String {
+            name: "EvalParams".to_string(),
+            default: Some(EMPTY_OPTION_VALUE.to_string()),
+        },
+    ];
+    // Switches for testing the forward pruning techniques one by one.
+    for name in PRUNING_OPTIONS {
+        options.push(UciOptionConfig::Check {
+            name: name.to_string(),
+            default: Some(true),
+        });
+    }
+    options
 }
 
 struct EngineBroker {
     current_game: Game,
     time_broker: TimeBroker,
+    /// Locked by the running search, so it is shared with its task.
+    cache: Arc<Mutex<TranspositionTable>>,
+    search_options: SearchOptions,
 }
 
+/// Run the engine on `commands` and send its answers to `output`.
+///
+/// Lines that `UciMessage` cannot express are sent as `UciMessage::Unknown` without an
+/// error and must be written as they are.
 pub async fn broker_loop(
     mut commands: UnboundedReceiver<EngineCommand>,
-    mut output: UnboundedSender<UciMessage>,
+    output: UnboundedSender<UciMessage>,
 ) {
     println!("info string EngineBroker started");
     let mut broker = EngineBroker::new();
 
     while let Some(command) = commands.recv().await {
-        broker.handle_command(command, &mut output).await;
+        broker.handle_command(command, &output).await;
     }
     println!("info string EngineBroker shutdown");
 }
@@ -71,6 +186,10 @@ impl EngineBroker {
         EngineBroker {
             current_game: Game::new(),
             time_broker: TimeBroker::new(),
+            cache: Arc::new(Mutex::new(TranspositionTable::new(
+                TranspositionTable::DEFAULT_SIZE_MB,
+            ))),
+            search_options: SearchOptions::default(),
         }
     }
 
@@ -95,11 +214,27 @@ impl EngineBroker {
                 output.send(answer).unwrap();
                 self.perft_with_nps(depth, output).await.unwrap();
             }
+            EngineCommand::Bench { depth } => {
+                let answer = UciMessage::info_string("Bench started.".to_string());
+                output.send(answer).unwrap();
+                self.bench(depth, output);
+            }
+            EngineCommand::Tactics { nodes } => {
+                let answer = UciMessage::info_string("Tactics started.".to_string());
+                output.send(answer).unwrap();
+                self.tactics(nodes, output);
+            }
             EngineCommand::EvalCurrentPosition => {
-                let answer = UciMessage::info_string(format!(
-                    "info cps {}",
-                    eval::evaluate_position(&self.current_game.position())
-                ));
+                let trace = eval::trace_position(
+                    self.current_game.position(),
+                    &self.search_options.eval_params,
+                );
+                for line in trace.to_string().lines() {
+                    output
+                        .send(UciMessage::info_string(line.to_string()))
+                        .unwrap();
+                }
+                let answer = UciMessage::info_string(format!("info cps {}", trace.score));
                 output.send(answer).unwrap();
             }
             EngineCommand::ShowBoard => {
@@ -110,12 +245,23 @@ impl EngineBroker {
             EngineCommand::Search {
                 time_control,
                 search_control,
+                ponder,
             } => {
-                self.search(time_control, search_control, output);
+                self.search(time_control, search_control, ponder, output);
+            }
+            EngineCommand::PonderHit => {
+                self.time_broker.start_clock();
             }
             EngineCommand::StopSearch => {
                 self.time_broker.send_stop();
             }
+            EngineCommand::SetOption { name, value } => {
+                self.set_option(&name, value, output);
+            }
+            EngineCommand::NewGame => {
+                self.current_game = Game::new();
+                self.with_cache("ucinewgame", output, TranspositionTable::clear);
+            }
             EngineCommand::IsReady => {
                 let answer = UciMessage::ReadyOk;
                 output.send(answer).unwrap();
@@ -133,14 +279,11 @@ impl EngineBroker {
             self.current_game = Game::new();
         }
 
-        match fen {
-            Some(fen_str) => {
-                self.current_game = match Game::from_str(fen_str.as_str()) {
-                    Ok(game) => game,
-                    Err(e) => return Err(e),
-                }
+        if let Some(fen_str) = fen {
+            self.current_game = match Game::from_str(fen_str.as_str()) {
+                Ok(game) => game,
+                Err(e) => return Err(e),
             }
-            None => {}
         };
 
         for mv in moves {
@@ -150,6 +293,115 @@ impl EngineBroker {
         Ok(())
     }
 
+    fn set_option(
+        &mut self,
+        name: &str,
+        value: Option<String>,
+        output: &UnboundedSender<UciMessage>,
+    ) {
+        match name {
+            // Only tells us that the GUI may send `go ponder`.
+            "Ponder" => {}
+            "Hash" => match value.as_deref().map(str::parse::<usize>) {
+                Some(Ok(size_mb)) => {
+                    let size_mb = size_mb.clamp(1, TranspositionTable::MAX_SIZE_MB);
+                    self.with_cache("Hash", output, |cache| {
+                        *cache = TranspositionTable::new(size_mb)
+                    });
+                }
+                _ => {
+                    let answer = UciMessage::info_string(format!(
+                        "Invalid value for option Hash - {}",
+                        value.unwrap_or_default()
+                    ));
+                    output.send(answer).unwrap();
+                }
+            },
+            "Clear Hash" => {
+                self.with_cache("Clear Hash", output, TranspositionTable::clear);
+            }
+            "Threads" => match value.as_deref().map(str::parse::<usize>) {
+                Some(Ok(threads)) => {
+                    self.search_options.threads = threads.clamp(1, MAX_THREADS);
+                }
+                _ => {
+                    let answer = UciMessage::info_string(format!(
+                        "Invalid value for option Threads - {}",
+                        value.unwrap_or_default()
+                    ));
+                    output.send(answer).unwrap();
+                }
+            },
+            "MultiPV" => match value.as_deref().map(str::parse::<usize>) {
+                Some(Ok(multi_pv)) => {
+                    self.search_options.multi_pv = multi_pv.clamp(1, MAX_MULTI_PV);
+                }
+                _ => {
+                    let answer = UciMessage::info_string(format!(
+                        "Invalid value for option MultiPV - {}",
+                        value.unwrap_or_default()
+                    ));
+                    output.send(answer).unwrap();
+                }
+            },
+            "ReverseFutilityPruning" | "FutilityPruning" | "Razoring" | "ProbCut" => {
+                match value.as_deref().map(str::parse::<bool>) {
+                    Some(Ok(enabled)) => {
+                        let switch = match name {
+                            "ReverseFutilityPruning" => {
+                                &mut self.search_options.reverse_futility_pruning
+                            }
+                            "FutilityPruning" => &mut self.search_options.futility_pruning,
+                            "Razoring" => &mut self.search_options.razoring,
+                            _ => &mut self.search_options.probcut,
+                        };
+                        *switch = enabled;
+                    }
+                    _ => {
+                        let answer = UciMessage::info_string(format!(
+                            "Invalid value for option {} - {}",
+                            name,
+                            value.unwrap_or_default()
+                        ));
+                        output.send(answer).unwrap();
+                    }
+                }
+            }
+            "EvalParams" => match value.as_deref().map(str::trim) {
+                None | Some("") | Some(EMPTY_OPTION_VALUE) => {
+                    self.set_eval_params(EvalParams::default(), output);
+                }
+                Some(path) => match EvalParams::load(Path::new(path)) {
+                    Ok(params) => {
+                        if !self.set_eval_params(params, output) {
+                            return;
+                        }
+                        let answer = UciMessage::info_string(format!(
+                            "Loaded evaluation parameters from {}",
+                            path
+                        ));
+                        output.send(answer).unwrap();
+                    }
+                    Err(e) => {
+                        let answer = UciMessage::info_string(format!(
+                            "Invalid value for option EvalParams - {}",
+                            e
+                        ));
+                        output.send(answer).unwrap();
+                    }
+                },
+            },
+            _ => {
+                let answer = UciMessage::info_string(format!(
+                    "Unknown option - {} {}",
+                    name,
+                    value.unwrap_or_default()
+                ));
+                output.send(answer).unwrap();
+            }
+        }
+    }
+
     async fn perft_with_nps(
         &self,
         depth: usize,
@@ -161,38 +413,180 @@ impl EngineBroker {
 
         let nps = (nodes as f64 / time.elapsed().as_secs_f64()) as u32;
 
-        let answer = UciMessage::Info {
-            0: vec![
-                UciInfoAttribute::Nodes(nodes.try_into().unwrap()),
-                UciInfoAttribute::Nps(nps.try_into().unwrap()),
-            ],
-        };
+        let answer = UciMessage::Info(vec![
+            UciInfoAttribute::Nodes(nodes.into()),
+            UciInfoAttribute::Nps(nps.into()),
+        ]);
         output.send(answer).unwrap();
 
         Ok(())
     }
 
+    /// Scores in the transposition table were computed with the old parameters, so the
+    /// parameters only change if the table can be cleared.
+    fn set_eval_params(
+        &mut self,
+        params: EvalParams,
+        output: &UnboundedSender<UciMessage>,
+    ) -> bool {
+        let cleared = self.with_cache("EvalParams", output, TranspositionTable::clear);
+        if cleared {
+            self.search_options.eval_params = Arc::new(params);
+        }
+        cleared
+    }
+
+    /// Change the transposition table, unless a search is running.
+    ///
+    /// The search holds the table until it ends, and waiting for it would block the
+    /// broker from reading the `stop` that ends `go infinite`. Returns if `change` was
+    /// applied.
+    fn with_cache(
+        &self,
+        command: &str,
+        output: &UnboundedSender<UciMessage>,
+        change: impl FnOnce(&mut TranspositionTable),
+    ) -> bool {
+        match self.cache.try_lock() {
+            Ok(mut cache) => {
+                change(&mut cache);
+                true
+            }
+            Err(TryLockError::WouldBlock) => {
+                let answer =
+                    UciMessage::info_string(format!("{} is refused during a search", command));
+                output.send(answer).unwrap();
+                false
+            }
+            Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+        }
+    }
+
+    /// Search every position of `BENCH_POSITIONS` with a fresh transposition table.
+    ///
+    /// The total node count changes with every change to the search and is a quick
+    /// check that a change to move ordering or pruning has the expected effect.
+    fn bench(&self, depth: usize, output: &UnboundedSender<UciMessage>) {
+        let time = Instant::now();
+        let mut nodes = 0;
+        let limits = SearchLimits {
+            depth: Some(depth),
+            ..SearchLimits::default()
+        };
+
+        for fen in BENCH_POSITIONS {
+            let game = Game::from_str(fen).unwrap();
+            let mut cache = TranspositionTable::new(BENCH_HASH_SIZE_MB);
+            let mut time_broker = TimeBroker::new();
+            let signals = time_broker.start_search();
+            let result = search::iterative_deepening(
+                &game,
+                &limits,
+                signals,
+                &mut cache,
+                &SearchOptions::default(),
+                output,
+            );
+            nodes += result.nodes;
+        }
+
+        let nps = (nodes as f64 / time.elapsed().as_secs_f64()) as u64;
+        let answer = UciMessage::info_string(format!("Bench nodes {} nps {}", nodes, nps));
+        output.send(answer).unwrap();
+    }
+
+    /// Search every position of `TACTICS_POSITIONS` with a fresh transposition table and
+    /// count the positions where the best move is found.
+    ///
+    /// A node limit keeps the result independent of the speed of the machine, so it
+    /// shows if a change to the search finds tactics faster.
+    fn tactics(&self, nodes: u64, output: &UnboundedSender<UciMessage>) {
+        let time = Instant::now();
+        let limits = SearchLimits {
+            nodes: Some(nodes),
+            ..SearchLimits::default()
+        };
+        let mut total = 0;
+        let mut solved = 0;
+
+        for line in TACTICS_POSITIONS
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+        {
+            let (game, best_moves, id) = match parse_epd(line) {
+                Some(position) => position,
+                None => {
+                    let answer = UciMessage::info_string(format!("Invalid EPD - {}", line));
+                    output.send(answer).unwrap();
+                    continue;
+                }
+            };
+            let mut cache = TranspositionTable::new(BENCH_HASH_SIZE_MB);
+            let mut time_broker = TimeBroker::new();
+            let signals = time_broker.start_search();
+            let result = search::iterative_deepening(
+                &game,
+                &limits,
+                signals,
+                &mut cache,
+                &self.search_options,
+                output,
+            );
+
+            total += 1;
+            let found = result.pv().first().copied();
+            let is_solved = matches!(found, Some(mv) if best_moves.contains(&mv));
+            if is_solved {
+                solved += 1;
+            }
+            let answer = UciMessage::info_string(format!(
+                "{} {} {}",
+                id,
+                if is_solved { "solved" } else { "failed" },
+                found.map_or_else(|| "0000".to_string(), |mv| mv.to_string())
+            ));
+            output.send(answer).unwrap();
+        }
+
+        let answer = UciMessage::info_string(format!(
+            "Tactics solved {} of {} in {:.1}s",
+            solved,
+            total,
+            time.elapsed().as_secs_f64()
+        ));
+        output.send(answer).unwrap();
+    }
+
     fn search(
         &mut self,
         time_control: Option<UciTimeControl>,
         search_control: Option<UciSearchControl>,
+        ponder: bool,
         output: &UnboundedSender<UciMessage>,
     ) {
         let game = self.current_game.clone();
+        let cache = self.cache.clone();
+        let search_options = self.search_options.clone();
         let moved_output = output.clone();
-        let mut cancel_receiver = self.time_broker.get_cancel_receiver();
-        let max_depth = search_control.map_or(None, |d| d.depth.map(|d| d as usize));
+        let signals = self.time_broker.start_search();
+        let mut cancel_receiver = signals.cancel.clone();
+        let mut ponderhit_receiver = signals.time_manager.clone();
+        let mut limits = search_control.map_or_else(SearchLimits::default, |sc| {
+            SearchLimits::from_search_control(&sc)
+        });
+        // Illegal moves are ignored, if none are left all moves are searched.
+        limits.search_moves.retain(|&mv| game.position().legal(mv));
 
         if let Some(tc) = time_control {
             self.time_broker
                 .seed_time_control(game.position().side_to_move(), &tc);
 
             match tc {
-                UciTimeControl::TimeLeft { .. } | UciTimeControl::MoveTime(..) => {
-                    let new_receiver_option = self.time_broker.start_timer();
-                    if let Some(new_receiver) = new_receiver_option {
-                        cancel_receiver = new_receiver;
-                    }
+                UciTimeControl::TimeLeft { .. } | UciTimeControl::MoveTime(..) if !ponder => {
+                    self.time_broker.start_clock();
+                }
+                UciTimeControl::Infinite => {
+                    limits.infinite = true;
                 }
                 _ => {}
             }
@@ -200,19 +594,88 @@ impl EngineBroker {
 
         tokio::spawn(async move {
             println!("info string SearchTask started");
-            let result =
-                search::iterative_deepening(&game, max_depth, cancel_receiver, &moved_output);
+            let search_output = moved_output.clone();
+            let infinite = limits.infinite;
+            let result = tokio::task::spawn_blocking(move || {
+                let mut cache = cache.lock().unwrap();
+                search::iterative_deepening(
+                    &game,
+                    &limits,
+                    signals,
+                    &mut cache,
+                    &search_options,
+                    &search_output,
+                )
+            })
+            .await
+            .unwrap();
 
-            let answer = UciMessage::BestMove {
-                best_move: *result.pv.first().unwrap(),
-                ponder: None,
-            };
-            moved_output.send(answer).unwrap();
+            // `bestmove` must not be sent before `stop` for `go infinite`, or before
+            // `stop` or `ponderhit` when pondering, even if the search ended early.
+            if infinite || ponder {
+                loop {
+                    if *cancel_receiver.borrow_and_update()
+                        || (ponder && ponderhit_receiver.has_changed().unwrap_or(true))
+                    {
+                        break;
+                    }
+                    tokio::select! {
+                        changed = cancel_receiver.changed() => if changed.is_err() {
+                            break;
+                        },
+                        changed = ponderhit_receiver.changed(), if ponder => if changed.is_err() {
+                            break;
+                        },
+                    }
+                }
+            }
+
+            match result.pv().first() {
+                Some(&best_move) => {
+                    let answer = UciMessage::BestMove {
+                        best_move,
+                        ponder: result.pv().get(1).copied(),
+                    };
+                    moved_output.send(answer).unwrap();
+                }
+                // Without a legal move the null move is the answer, which `BestMove`
+                // cannot express. It is sent as a raw line, see `broker_loop`.
+                None => {
+                    let answer = UciMessage::Unknown("bestmove 0000".to_string(), None);
+                    moved_output.send(answer).unwrap();
+                }
+            }
             println!("info string SearchTask shutdown");
         });
     }
 }
 
+/// Position, best moves and id of an EPD line like
+/// `<board> <side> <castling> <en passant> bm Qg6; id "WAC.001";`.
+fn parse_epd(line: &str) -> Option<(Game, Vec<ChessMove>, String)> {
+    let tokens = line.split_whitespace().collect::<Vec<_>>();
+    if tokens.len() < 4 {
+        return None;
+    }
+    let game = Game::from_str(&format!("{} 0 1", tokens[..4].join(" "))).ok()?;
+
+    let mut best_moves = Vec::new();
+    let mut id = String::new();
+    for operation in tokens[4..].join(" ").split(';').map(str::trim) {
+        if let Some(moves) = operation.strip_prefix("bm ") {
+            for san in moves.split_whitespace() {
+                best_moves.push(ChessMove::from_san(game.position(), san).ok()?);
+            }
+        } else if let Some(value) = operation.strip_prefix("id ") {
+            id = value.trim_matches('"').to_string();
+        }
+    }
+    if best_moves.is_empty() {
+        return None;
+    }
+    Some((game, best_moves, id))
+}
+
 fn perft(game: &Game, depth: usize) -> u32 {
     let movegen = MoveGen::new_legal(game.position());
 
@@ -229,3 +692,28 @@ fn perft(game: &Game, depth: usize) -> u32 {
 
     count_nodes_total
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tactics_positions_are_valid() {
+        let lines = TACTICS_POSITIONS
+            .lines()
+            .filter(|line| !line.trim().is_empty());
+        for line in lines {
+            assert!(parse_epd(line).is_some(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn parse_epd_with_best_moves_and_id() {
+        let (game, best_moves, id) =
+            parse_epd("4k3/8/8/8/8/8/4P3/4K3 w - - bm e4 e3; id \"test\";").unwrap();
+        assert_eq!(game.position().side_to_move(), chess::Color::White);
+        assert_eq!(best_moves.len(), 2);
+        assert_eq!(id, "test");
+        assert!(parse_epd("4k3/8/8/8/8/8/4P3/4K3 w - - bm e5;").is_none());
+    }
+}
@@ -0,0 +1,269 @@
+use chess::{get_rank, BitBoard, Board, ChessMove, Color, MoveGen, Piece};
+
+use crate::see;
+
+/// Number of killer moves remembered per ply.
+const KILLERS_PER_PLY: usize = 2;
+/// History scores are kept within this bound, so recent cutoffs outweigh old ones.
+const MAX_HISTORY: i32 = 16384;
+
+/// Score of a capture: the most valuable victim first, then the least valuable attacker.
+fn mvv_lva(board: &Board, mv: ChessMove) -> i32 {
+    // A pawn moving to an empty square of another file captures en passant.
+    let victim = board.piece_on(mv.get_dest()).unwrap_or(Piece::Pawn);
+    let attacker = board.piece_on(mv.get_source()).unwrap_or(Piece::Pawn);
+    let promotion = mv
+        .get_promotion()
+        .map_or(0, |piece| piece.to_index() as i32);
+    victim.to_index() as i32 * 8 - attacker.to_index() as i32 + promotion * 8
+}
+
+/// Captures and queen promotions, the moves searched before the killers.
+pub fn is_noisy(board: &Board, mv: ChessMove) -> bool {
+    board.piece_on(mv.get_dest()).is_some()
+        || mv.get_promotion() == Some(Piece::Queen)
+        || (board.piece_on(mv.get_source()) == Some(Piece::Pawn)
+            && mv.get_source().get_file() != mv.get_dest().get_file())
+}
+
+/// Moves that caused a beta cutoff, by side to move, source and destination square.
+///
+/// Quiet moves that refuted a position are likely to refute similar positions
+/// elsewhere in the tree.
+pub struct HistoryTable {
+    scores: Box<[[[i32; 64]; 64]; 2]>,
+}
+
+impl HistoryTable {
+    pub fn new() -> HistoryTable {
+        HistoryTable {
+            scores: Box::new([[[0; 64]; 64]; 2]),
+        }
+    }
+
+    pub fn get(&self, color: Color, mv: ChessMove) -> i32 {
+        self.scores[color.to_index()][mv.get_source().to_index()][mv.get_dest().to_index()]
+    }
+
+    /// Reward a quiet move that caused a cutoff at `depth` and punish the quiet moves
+    /// searched before it.
+    pub fn update(
+        &mut self,
+        color: Color,
+        cutoff_move: ChessMove,
+        tried: &[ChessMove],
+        depth: usize,
+    ) {
+        let bonus = (depth * depth).min(MAX_HISTORY as usize) as i32;
+        self.add(color, cutoff_move, bonus);
+        for &mv in tried {
+            self.add(color, mv, -bonus);
+        }
+    }
+
+    fn add(&mut self, color: Color, mv: ChessMove, bonus: i32) {
+        let score = &mut self.scores[color.to_index()][mv.get_source().to_index()]
+            [mv.get_dest().to_index()];
+        // Scale the bonus down the closer the score is to the bound.
+        *score += bonus - *score * bonus.abs() / MAX_HISTORY;
+    }
+}
+
+/// Quiet moves that caused a beta cutoff, by ply.
+pub struct KillerMoves {
+    killers: Vec<[Option<ChessMove>; KILLERS_PER_PLY]>,
+}
+
+impl KillerMoves {
+    pub fn new(max_ply: usize) -> KillerMoves {
+        KillerMoves {
+            killers: vec![[None; KILLERS_PER_PLY]; max_ply],
+        }
+    }
+
+    pub fn get(&self, ply: usize) -> [Option<ChessMove>; KILLERS_PER_PLY] {
+        self.killers
+            .get(ply)
+            .copied()
+            .unwrap_or([None; KILLERS_PER_PLY])
+    }
+
+    pub fn add(&mut self, ply: usize, mv: ChessMove) {
+        if let Some(killers) = self.killers.get_mut(ply) {
+            if killers[0] != Some(mv) {
+                killers[1] = killers[0];
+                killers[0] = Some(mv);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Stage {
+    HashMove,
+    Generate,
+    GoodCaptures,
+    Killers,
+    Quiets,
+    BadCaptures,
+    Done,
+}
+
+struct ScoredMove {
+    mv: ChessMove,
+    score: i32,
+}
+
+/// Yields the legal moves of a position in the order they should be searched: the
+/// hash move, captures by MVV-LVA that do not lose material, the killer moves, quiet
+/// moves by history and finally the losing captures.
+///
+/// Moves are only generated once the hash move failed to cause a cutoff, and each
+/// stage only sorts as far as it is consumed.
+pub struct MovePicker<'a> {
+    board: &'a Board,
+    stage: Stage,
+    hash_move: Option<ChessMove>,
+    killers: [Option<ChessMove>; KILLERS_PER_PLY],
+    killer_index: usize,
+    captures: Vec<ScoredMove>,
+    quiets: Vec<ScoredMove>,
+    /// Captures with a negative static exchange evaluation, in MVV-LVA order.
+    bad_captures: Vec<ChessMove>,
+    bad_capture_index: usize,
+    /// Only yield captures and queen promotions, for the quiescence search.
+    noisy_only: bool,
+}
+
+impl<'a> MovePicker<'a> {
+    /// The hash move has to be legal in `board`.
+    pub fn new(
+        board: &'a Board,
+        hash_move: Option<ChessMove>,
+        killers: [Option<ChessMove>; KILLERS_PER_PLY],
+    ) -> MovePicker<'a> {
+        MovePicker {
+            board,
+            stage: Stage::HashMove,
+            hash_move,
+            killers,
+            killer_index: 0,
+            captures: Vec::new(),
+            quiets: Vec::new(),
+            bad_captures: Vec::new(),
+            bad_capture_index: 0,
+            noisy_only: false,
+        }
+    }
+
+    /// Picker for captures and queen promotions only, ordered by MVV-LVA. Captures that
+    /// lose material by static exchange evaluation are left out.
+    pub fn new_noisy(board: &'a Board) -> MovePicker<'a> {
+        MovePicker {
+            noisy_only: true,
+            ..MovePicker::new(board, None, [None; KILLERS_PER_PLY])
+        }
+    }
+
+    /// Next move to search, quiet moves are ordered by `history`.
+    pub fn next(&mut self, history: &HistoryTable) -> Option<ChessMove> {
+        loop {
+            match self.stage {
+                Stage::HashMove => {
+                    self.stage = Stage::Generate;
+                    if self.hash_move.is_some() {
+                        return self.hash_move;
+                    }
+                }
+                Stage::Generate => {
+                    self.generate();
+                    self.stage = Stage::GoodCaptures;
+                }
+                Stage::GoodCaptures => match pick_best(&mut self.captures) {
+                    // Captures that lose material are only searched after the quiet moves.
+                    Some(mv) if see::see(self.board, mv) < 0 => {
+                        if !self.noisy_only {
+                            self.bad_captures.push(mv);
+                        }
+                    }
+                    Some(mv) => return Some(mv),
+                    None if self.noisy_only => self.stage = Stage::Done,
+                    None => self.stage = Stage::Killers,
+                },
+                Stage::Killers => {
+                    while self.killer_index < KILLERS_PER_PLY {
+                        let killer = self.killers[self.killer_index];
+                        self.killer_index += 1;
+                        // Killers come from other positions, they are only searched if
+                        // they are among the generated quiet moves.
+                        if let Some(index) = self
+                            .quiets
+                            .iter()
+                            .position(|scored| Some(scored.mv) == killer)
+                        {
+                            return Some(self.quiets.swap_remove(index).mv);
+                        }
+                    }
+                    let color = self.board.side_to_move();
+                    for scored in &mut self.quiets {
+                        scored.score = history.get(color, scored.mv);
+                    }
+                    self.stage = Stage::Quiets;
+                }
+                Stage::Quiets => match pick_best(&mut self.quiets) {
+                    Some(mv) => return Some(mv),
+                    None => self.stage = Stage::BadCaptures,
+                },
+                Stage::BadCaptures => {
+                    if self.bad_capture_index < self.bad_captures.len() {
+                        self.bad_capture_index += 1;
+                        return Some(self.bad_captures[self.bad_capture_index - 1]);
+                    }
+                    self.stage = Stage::Done;
+                }
+                Stage::Done => return None,
+            }
+        }
+    }
+
+    fn generate(&mut self) {
+        let mut movegen = MoveGen::new_legal(self.board);
+        if self.noisy_only {
+            let side_to_move = self.board.side_to_move();
+            let en_passant = self.board.en_passant().map_or(BitBoard::new(0), |square| {
+                BitBoard::from_square(square.uforward(side_to_move))
+            });
+            movegen.set_iterator_mask(
+                self.board.color_combined(!side_to_move)
+                    | en_passant
+                    | get_rank(side_to_move.to_their_backrank()),
+            );
+        }
+
+        for mv in movegen {
+            if Some(mv) == self.hash_move {
+                continue;
+            }
+            if is_noisy(self.board, mv) {
+                self.captures.push(ScoredMove {
+                    mv,
+                    score: mvv_lva(self.board, mv),
+                });
+            } else if !self.noisy_only {
+                self.quiets.push(ScoredMove { mv, score: 0 });
+            }
+        }
+    }
+}
+
+/// Remove and return the move with the highest score.
+///
+/// A full sort is wasted on nodes that cut off after the first few moves.
+fn pick_best(moves: &mut Vec<ScoredMove>) -> Option<ChessMove> {
+    let best = moves
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, scored)| scored.score)
+        .map(|(index, _)| index)?;
+    Some(moves.swap_remove(best).mv)
+}
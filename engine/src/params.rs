@@ -0,0 +1,314 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::weights::{self, Weight};
+
+/// Bound of the midgame and endgame value of a weight, far above any sensible value.
+/// The bounds keep the evaluation from overflowing.
+const MAX_WEIGHT: i32 = 4000;
+/// Bound of the attack units of `king_zone_attack` and `king_safe_check`.
+const MAX_ATTACK_UNITS: i32 = 100;
+/// Bound of `king_attack_danger`, which is multiplied with the squared attack units.
+const MAX_ATTACK_DANGER: i32 = 16;
+
+/// All weights of the evaluation, so weight sets can be compared without a rebuild.
+///
+/// The defaults are the constants of `weights`. Parameter files are JSON or TOML by
+/// their extension, missing fields keep their default.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EvalParams {
+    pub pawn_mv: Weight,
+    pub knight_mv: Weight,
+    pub bishop_mv: Weight,
+    pub rook_mv: Weight,
+    pub queen_mv: Weight,
+    pub king_mv: Weight,
+    #[serde(with = "big_array")]
+    pub pawn_pst: [Weight; 64],
+    #[serde(with = "big_array")]
+    pub knight_pst: [Weight; 64],
+    #[serde(with = "big_array")]
+    pub bishop_pst: [Weight; 64],
+    #[serde(with = "big_array")]
+    pub rook_pst: [Weight; 64],
+    #[serde(with = "big_array")]
+    pub queen_pst: [Weight; 64],
+    #[serde(with = "big_array")]
+    pub king_pst: [Weight; 64],
+
+    pub pair_mod_bishop: Weight,
+    pub pair_mod_knight: Weight,
+    pub pair_mod_rook: Weight,
+    pub tempo_mod: Weight,
+
+    pub knight_mobility: [Weight; 9],
+    pub bishop_mobility: [Weight; 14],
+    pub rook_mobility: [Weight; 15],
+    pub queen_mobility: [Weight; 28],
+    pub rook_open_file: Weight,
+    pub rook_semi_open_file: Weight,
+    pub rook_on_seventh: Weight,
+    pub knight_outpost: Weight,
+    pub bishop_outpost: Weight,
+    pub bad_bishop_pawn: Weight,
+    pub trapped_bishop: Weight,
+    pub trapped_rook: Weight,
+
+    pub passed_pawn: [Weight; 8],
+    pub passed_pawn_blocked: Weight,
+    pub passed_pawn_enemy_king_distance: Weight,
+    pub passed_pawn_own_king_distance: Weight,
+    pub doubled_pawn: Weight,
+    pub isolated_pawn: Weight,
+    pub backward_pawn: Weight,
+    pub connected_pawn: Weight,
+    pub pawn_island: Weight,
+
+    pub king_zone_attack: [i32; 6],
+    pub king_safe_check: [i32; 6],
+    pub king_attack_danger: Weight,
+    pub king_pawn_shield: [Weight; 3],
+    pub king_pawn_storm: [Weight; 3],
+    pub king_open_file: Weight,
+    pub king_semi_open_file: Weight,
+}
+
+impl EvalParams {
+    pub const DEFAULT: EvalParams = EvalParams {
+        pawn_mv: weights::PAWN_MV,
+        knight_mv: weights::KNIGHT_MV,
+        bishop_mv: weights::BISHOP_MV,
+        rook_mv: weights::ROOK_MV,
+        queen_mv: weights::QUEEN_MV,
+        king_mv: weights::KING_MV,
+        pawn_pst: weights::PAWN_PST,
+        knight_pst: weights::KNIGHT_PST,
+        bishop_pst: weights::BISHOP_PST,
+        rook_pst: weights::ROOK_PST,
+        queen_pst: weights::QUEEN_PST,
+        king_pst: weights::KING_PST,
+
+        pair_mod_bishop: weights::PAIR_MOD_BISHOP,
+        pair_mod_knight: weights::PAIR_MOD_KNIGHT,
+        pair_mod_rook: weights::PAIR_MOD_ROOK,
+        tempo_mod: weights::TEMPO_MOD,
+
+        knight_mobility: weights::KNIGHT_MOBILITY,
+        bishop_mobility: weights::BISHOP_MOBILITY,
+        rook_mobility: weights::ROOK_MOBILITY,
+        queen_mobility: weights::QUEEN_MOBILITY,
+        rook_open_file: weights::ROOK_OPEN_FILE,
+        rook_semi_open_file: weights::ROOK_SEMI_OPEN_FILE,
+        rook_on_seventh: weights::ROOK_ON_SEVENTH,
+        knight_outpost: weights::KNIGHT_OUTPOST,
+        bishop_outpost: weights::BISHOP_OUTPOST,
+        bad_bishop_pawn: weights::BAD_BISHOP_PAWN,
+        trapped_bishop: weights::TRAPPED_BISHOP,
+        trapped_rook: weights::TRAPPED_ROOK,
+
+        passed_pawn: weights::PASSED_PAWN,
+        passed_pawn_blocked: weights::PASSED_PAWN_BLOCKED,
+        passed_pawn_enemy_king_distance: weights::PASSED_PAWN_ENEMY_KING_DISTANCE,
+        passed_pawn_own_king_distance: weights::PASSED_PAWN_OWN_KING_DISTANCE,
+        doubled_pawn: weights::DOUBLED_PAWN,
+        isolated_pawn: weights::ISOLATED_PAWN,
+        backward_pawn: weights::BACKWARD_PAWN,
+        connected_pawn: weights::CONNECTED_PAWN,
+        pawn_island: weights::PAWN_ISLAND,
+
+        king_zone_attack: weights::KING_ZONE_ATTACK,
+        king_safe_check: weights::KING_SAFE_CHECK,
+        king_attack_danger: weights::KING_ATTACK_DANGER,
+        king_pawn_shield: weights::KING_PAWN_SHIELD,
+        king_pawn_storm: weights::KING_PAWN_STORM,
+        king_open_file: weights::KING_OPEN_FILE,
+        king_semi_open_file: weights::KING_SEMI_OPEN_FILE,
+    };
+
+    /// Read parameters from a `.json` or `.toml` file.
+    ///
+    /// Weights outside of their bounds are rejected.
+    pub fn load(path: &Path) -> Result<EvalParams, EvalParamsError> {
+        let content = fs::read_to_string(path).map_err(EvalParamsError::Io)?;
+        let params: EvalParams = match Format::of(path)? {
+            Format::Json => serde_json::from_str(&content).map_err(EvalParamsError::Json)?,
+            Format::Toml => toml::from_str(&content).map_err(EvalParamsError::TomlRead)?,
+        };
+        params.check_bounds()?;
+        Ok(params)
+    }
+
+    /// Write the parameters to a `.json` or `.toml` file.
+    ///
+    /// Weights outside of their bounds are rejected, `load` could not read them.
+    pub fn save(&self, path: &Path) -> Result<(), EvalParamsError> {
+        self.check_bounds()?;
+        let content = match Format::of(path)? {
+            Format::Json => serde_json::to_string_pretty(self).map_err(EvalParamsError::Json)?,
+            Format::Toml => toml::to_string(self).map_err(EvalParamsError::TomlWrite)?,
+        };
+        fs::write(path, content).map_err(EvalParamsError::Io)
+    }
+
+    /// Largest magnitude of the weights of the field `name`, as accepted by `load`.
+    pub fn bound(name: &str) -> i32 {
+        match name {
+            "king_zone_attack" | "king_safe_check" => MAX_ATTACK_UNITS,
+            "king_attack_danger" => MAX_ATTACK_DANGER,
+            _ => MAX_WEIGHT,
+        }
+    }
+
+    /// Check every weight against the bound of its field.
+    fn check_bounds(&self) -> Result<(), EvalParamsError> {
+        fn within(value: &serde_json::Value, bound: i32) -> bool {
+            match value {
+                serde_json::Value::Number(number) => {
+                    matches!(number.as_i64(), Some(n) if n.abs() <= bound as i64)
+                }
+                serde_json::Value::Array(array) => array.iter().all(|value| within(value, bound)),
+                _ => true,
+            }
+        }
+
+        let fields = match serde_json::to_value(self).map_err(EvalParamsError::Json)? {
+            serde_json::Value::Object(fields) => fields,
+            _ => return Ok(()),
+        };
+        for (name, value) in fields {
+            let bound = EvalParams::bound(&name);
+            if !within(&value, bound) {
+                return Err(EvalParamsError::OutOfRange { name, bound });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for EvalParams {
+    fn default() -> Self {
+        EvalParams::DEFAULT
+    }
+}
+
+enum Format {
+    Json,
+    Toml,
+}
+
+impl Format {
+    fn of(path: &Path) -> Result<Format, EvalParamsError> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Ok(Format::Json),
+            Some("toml") => Ok(Format::Toml),
+            _ => Err(EvalParamsError::UnknownFormat),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum EvalParamsError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    TomlRead(toml::de::Error),
+    TomlWrite(toml::ser::Error),
+    UnknownFormat,
+    /// A weight of the field `name` is larger than `bound` in magnitude.
+    OutOfRange {
+        name: String,
+        bound: i32,
+    },
+}
+
+impl fmt::Display for EvalParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalParamsError::Io(e) => write!(f, "cannot access file - {}", e),
+            EvalParamsError::Json(e) => write!(f, "invalid JSON - {}", e),
+            EvalParamsError::TomlRead(e) => write!(f, "invalid TOML - {}", e),
+            EvalParamsError::TomlWrite(e) => write!(f, "cannot write TOML - {}", e),
+            EvalParamsError::UnknownFormat => write!(f, "file extension is not .json or .toml"),
+            EvalParamsError::OutOfRange { name, bound } => {
+                write!(f, "{} is outside of -{}..={}", name, bound, bound)
+            }
+        }
+    }
+}
+
+/// Serde only implements arrays of up to 32 elements, the piece square tables are
+/// stored as sequences.
+mod big_array {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use crate::weights::Weight;
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        array: &[Weight; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(array)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[Weight; N], D::Error> {
+        let weights = Vec::<Weight>::deserialize(deserializer)?;
+        let length = weights.len();
+        weights
+            .try_into()
+            .map_err(|_| D::Error::invalid_length(length, &format!("{} weights", N).as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_within_bounds() {
+        assert!(EvalParams::default().check_bounds().is_ok());
+    }
+
+    #[test]
+    fn weights_out_of_bounds_are_rejected() {
+        let params = EvalParams {
+            tempo_mod: Weight::new(0, -MAX_WEIGHT - 1),
+            ..EvalParams::default()
+        };
+        assert!(matches!(params.check_bounds(),
+            Err(EvalParamsError::OutOfRange { name, .. }) if name == "tempo_mod"));
+
+        let mut params = EvalParams::default();
+        params.king_safe_check[3] = MAX_ATTACK_UNITS + 1;
+        assert!(matches!(params.check_bounds(),
+            Err(EvalParamsError::OutOfRange { name, .. }) if name == "king_safe_check"));
+    }
+
+    #[test]
+    fn save_rejects_weights_out_of_bounds() {
+        let path = std::env::temp_dir().join("engine_params_save_out_of_bounds.json");
+        let params = EvalParams {
+            king_attack_danger: Weight::new(-MAX_ATTACK_DANGER - 1, 0),
+            ..EvalParams::default()
+        };
+        assert!(matches!(
+            params.save(&path),
+            Err(EvalParamsError::OutOfRange { .. })
+        ));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn load_rejects_weights_out_of_bounds() {
+        let path = std::env::temp_dir().join("engine_params_out_of_bounds.json");
+        fs::write(&path, r#"{ "king_attack_danger": [-100, 0] }"#).unwrap();
+        let result = EvalParams::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(EvalParamsError::OutOfRange { .. })));
+    }
+}
@@ -0,0 +1,304 @@
+use chess::{get_adjacent_files, get_file, BitBoard, Board, Color, Piece, Square, EMPTY};
+
+use crate::{params::EvalParams, weights::Weight};
+
+/// Number of entries of a pawn hash table, a power of two.
+const PAWN_HASH_ENTRIES: usize = 1 << 14;
+
+/// Random keys for the pawns of each color on each square.
+const PAWN_KEYS: [[u64; 64]; 2] = pawn_keys();
+
+/// SplitMix64, used to fill `PAWN_KEYS` at compile time.
+const fn pawn_keys() -> [[u64; 64]; 2] {
+    let mut keys = [[0; 64]; 2];
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut index = 0;
+    while index < 128 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut key = state;
+        key = (key ^ (key >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        key = (key ^ (key >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        keys[index / 64][index % 64] = key ^ (key >> 31);
+        index += 1;
+    }
+    keys
+}
+
+/// Zobrist hash of the pawns only.
+///
+/// `Board::get_pawn_hash` of the chess crate is not implemented and always returns 0.
+fn pawn_hash(board: &Board) -> u64 {
+    let mut hash = 0;
+    for color in [Color::White, Color::Black] {
+        for square in board.pieces(Piece::Pawn) & board.color_combined(color) {
+            hash ^= PAWN_KEYS[color.to_index()][square.to_index()];
+        }
+    }
+    hash
+}
+
+#[derive(Clone, Copy, Default)]
+struct PawnEntry {
+    key: u64,
+    /// Score of the terms that only depend on pawns, by color.
+    score: [Weight; 2],
+    /// Passed pawns by color.
+    passed: [BitBoard; 2],
+}
+
+/// Cache of the pawn structure evaluation indexed by the pawn hash.
+///
+/// Pawn structures change rarely during a search, so almost all lookups hit.
+///
+/// Entries are scored with the `EvalParams` of the evaluation that stored them and the
+/// key does not cover the parameters, so a table must not be shared between different
+/// parameters. Use a new table after changing them.
+pub struct PawnHashTable {
+    entries: Vec<PawnEntry>,
+}
+
+impl Default for PawnHashTable {
+    fn default() -> Self {
+        PawnHashTable::new()
+    }
+}
+
+impl PawnHashTable {
+    pub fn new() -> PawnHashTable {
+        PawnHashTable {
+            entries: vec![PawnEntry::default(); PAWN_HASH_ENTRIES],
+        }
+    }
+
+    fn probe(&mut self, board: &Board, params: &EvalParams) -> PawnEntry {
+        let key = pawn_hash(board);
+        let index = key as usize & (PAWN_HASH_ENTRIES - 1);
+        let entry = self.entries[index];
+        // Key 0 is the position without pawns, the empty entry is correct for it.
+        if entry.key == key {
+            return entry;
+        }
+
+        let white = evaluate_pawns_for_color(board, params, Color::White);
+        let black = evaluate_pawns_for_color(board, params, Color::Black);
+        let entry = PawnEntry {
+            key,
+            score: [white.0, black.0],
+            passed: [white.1, black.1],
+        };
+        self.entries[index] = entry;
+        entry
+    }
+}
+
+/// Evaluate the pawn structure of both colors, indexed by color.
+pub fn evaluate_pawn_structure(
+    board: &Board,
+    params: &EvalParams,
+    pawn_table: &mut PawnHashTable,
+) -> [Weight; 2] {
+    let entry = pawn_table.probe(board, params);
+    [Color::White, Color::Black].map(|color| {
+        let index = color.to_index();
+        entry.score[index]
+            + evaluate_passed_pawns_for_color(board, params, color, entry.passed[index])
+    })
+}
+
+/// Rank of the square from the view of `color`, 0 for the own back rank.
+pub fn relative_rank(color: Color, square: Square) -> usize {
+    match color {
+        Color::White => square.get_rank().to_index(),
+        Color::Black => 7 - square.get_rank().to_index(),
+    }
+}
+
+/// The square in front of a pawn of `color`.
+fn stop_square(color: Color, square: Square) -> Option<Square> {
+    match color {
+        Color::White => square.up(),
+        Color::Black => square.down(),
+    }
+}
+
+/// All squares on ranks in front of `square` from the view of `color`.
+pub fn ranks_in_front(color: Color, square: Square) -> BitBoard {
+    let rank = square.get_rank().to_index();
+    match color {
+        Color::White if rank < 7 => BitBoard(!0 << (8 * (rank + 1))),
+        Color::Black => BitBoard((1 << (8 * rank)) - 1),
+        _ => EMPTY,
+    }
+}
+
+/// Score and passed pawns of the terms that only depend on the pawns of both colors.
+fn evaluate_pawns_for_color(
+    board: &Board,
+    params: &EvalParams,
+    color: Color,
+) -> (Weight, BitBoard) {
+    let pawns = board.pieces(Piece::Pawn);
+    let own_pawns = pawns & board.color_combined(color);
+    let enemy_pawns = pawns & board.color_combined(!color);
+    let mut score = Weight::default();
+    let mut passed = EMPTY;
+
+    for square in own_pawns {
+        let file = square.get_file();
+        let in_front = ranks_in_front(color, square);
+        let adjacent_files = get_adjacent_files(file);
+
+        if enemy_pawns & in_front & (get_file(file) | adjacent_files) == EMPTY {
+            passed |= BitBoard::from_square(square);
+            score += params.passed_pawn[relative_rank(color, square)];
+        }
+
+        if own_pawns & adjacent_files == EMPTY {
+            score += params.isolated_pawn;
+        } else {
+            // Defended by a pawn or next to one on the same rank.
+            let supported = chess::get_pawn_attacks(square, !color, own_pawns) != EMPTY;
+            let phalanx = own_pawns & adjacent_files & chess::get_rank(square.get_rank()) != EMPTY;
+            if supported || phalanx {
+                score += params.connected_pawn;
+            } else if own_pawns & adjacent_files & !in_front == EMPTY {
+                // No pawn on an adjacent file can come to support it, and it cannot
+                // advance safely either.
+                if matches!(stop_square(color, square),
+                    Some(stop) if chess::get_pawn_attacks(stop, color, enemy_pawns) != EMPTY)
+                {
+                    score += params.backward_pawn;
+                }
+            }
+        }
+    }
+
+    let mut islands = 0;
+    let mut previous_file_has_pawns = false;
+    for file in chess::ALL_FILES {
+        let has_pawns = own_pawns & get_file(file) != EMPTY;
+        if has_pawns {
+            let count = (own_pawns & get_file(file)).popcnt() as i32;
+            score += params.doubled_pawn * (count - 1);
+            if !previous_file_has_pawns {
+                islands += 1;
+            }
+        }
+        previous_file_has_pawns = has_pawns;
+    }
+    score += params.pawn_island * (islands - 1).max(0);
+
+    (score, passed)
+}
+
+/// Terms of passed pawns that depend on other pieces: blockers and king distances.
+fn evaluate_passed_pawns_for_color(
+    board: &Board,
+    params: &EvalParams,
+    color: Color,
+    passed: BitBoard,
+) -> Weight {
+    let mut score = Weight::default();
+    let own_king = board.king_square(color);
+    let enemy_king = board.king_square(!color);
+
+    for square in passed {
+        let stop_square = match stop_square(color, square) {
+            Some(stop_square) => stop_square,
+            None => continue,
+        };
+
+        if board.piece_on(stop_square).is_some() {
+            score += params.passed_pawn_blocked;
+        }
+
+        // The kings matter more the further the pawn has advanced.
+        let rank_scale = relative_rank(color, square).saturating_sub(2) as i32;
+        score += (params.passed_pawn_enemy_king_distance * distance(enemy_king, stop_square)
+            + params.passed_pawn_own_king_distance * distance(own_king, stop_square))
+            * rank_scale;
+    }
+    score
+}
+
+/// Number of king moves between two squares.
+fn distance(a: Square, b: Square) -> i32 {
+    let rank_distance = (a.get_rank().to_index() as i32 - b.get_rank().to_index() as i32).abs();
+    let file_distance = (a.get_file().to_index() as i32 - b.get_file().to_index() as i32).abs();
+    rank_distance.max(file_distance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    /// White pawns a2, c2, c3 and e5, black pawns b4 and h7.
+    const FEN: &str = "4k3/7p/8/4P3/1p6/2P5/P1P5/4K3 w - - 0 1";
+
+    /// Parameters where only the term set by `term` counts, once per occurrence.
+    fn count(color: Color, term: fn(&mut EvalParams)) -> (i32, BitBoard) {
+        let mut params = EvalParams {
+            passed_pawn: [Weight::default(); 8],
+            doubled_pawn: Weight::default(),
+            isolated_pawn: Weight::default(),
+            backward_pawn: Weight::default(),
+            connected_pawn: Weight::default(),
+            pawn_island: Weight::default(),
+            ..EvalParams::default()
+        };
+        term(&mut params);
+        let board = Board::from_str(FEN).unwrap();
+        let (score, passed) = evaluate_pawns_for_color(&board, &params, color);
+        (score.mg, passed)
+    }
+
+    #[test]
+    fn isolated_pawns() {
+        let term = |params: &mut EvalParams| params.isolated_pawn = Weight::new(1, 0);
+        assert_eq!(count(Color::White, term).0, 4);
+        assert_eq!(count(Color::Black, term).0, 2);
+    }
+
+    #[test]
+    fn doubled_pawns() {
+        let term = |params: &mut EvalParams| params.doubled_pawn = Weight::new(1, 0);
+        assert_eq!(count(Color::White, term).0, 1);
+        assert_eq!(count(Color::Black, term).0, 0);
+    }
+
+    #[test]
+    fn pawn_islands() {
+        let term = |params: &mut EvalParams| params.pawn_island = Weight::new(1, 0);
+        assert_eq!(count(Color::White, term).0, 2);
+        assert_eq!(count(Color::Black, term).0, 1);
+    }
+
+    #[test]
+    fn passed_pawns() {
+        // The score is the relative rank of the passed pawn.
+        let term = |params: &mut EvalParams| {
+            params.passed_pawn = [0, 1, 2, 3, 4, 5, 6, 7].map(|rank| Weight::new(rank, 0))
+        };
+        assert_eq!(
+            count(Color::White, term),
+            (4, BitBoard::from_square(Square::E5))
+        );
+        assert_eq!(
+            count(Color::Black, term),
+            (1, BitBoard::from_square(Square::H7))
+        );
+    }
+
+    #[test]
+    fn table_returns_the_computed_entry() {
+        let board = Board::from_str(FEN).unwrap();
+        let params = EvalParams::default();
+        let mut table = PawnHashTable::new();
+        let computed = evaluate_pawn_structure(&board, &params, &mut table);
+        assert_eq!(
+            evaluate_pawn_structure(&board, &params, &mut table),
+            computed
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use chess::{
+    get_adjacent_files, get_bishop_moves, get_file, get_knight_moves, get_pawn_attacks,
+    get_rook_moves, BitBoard, Board, Color, Piece, Square, EMPTY,
+};
+
+use crate::{
+    params::EvalParams,
+    pawns::{ranks_in_front, relative_rank},
+    weights::Weight,
+};
+
+/// Squares a1 is not the color of.
+const LIGHT_SQUARES: BitBoard = BitBoard(0x55AA_55AA_55AA_55AA);
+
+/// Scores of the knights, bishops, rooks and queens of one color.
+#[derive(Clone, Copy, Default)]
+pub struct PieceScores {
+    pub mobility: Weight,
+    /// Files, ranks and squares the pieces stand on.
+    pub placement: Weight,
+}
+
+/// Mobility and placement of the knights, bishops, rooks and queens of `color`.
+pub fn evaluate_pieces(board: &Board, params: &EvalParams, color: Color) -> PieceScores {
+    let occupied = *board.combined();
+    let own = board.color_combined(color);
+    let pawns = board.pieces(Piece::Pawn);
+    let own_pawns = pawns & own;
+    let enemy_pawns = pawns & board.color_combined(!color);
+
+    let mut enemy_pawn_attacks = EMPTY;
+    for square in enemy_pawns {
+        enemy_pawn_attacks |= get_pawn_attacks(square, !color, !EMPTY);
+    }
+    // Squares attacked by enemy pawns are not safe to move to, and squares of the own
+    // pawns and king are rarely freed.
+    let mobility_area = !(own_pawns | (board.pieces(Piece::King) & own) | enemy_pawn_attacks);
+
+    let mut score = PieceScores::default();
+    for square in board.pieces(Piece::Knight) & own {
+        let mobility = (get_knight_moves(square) & mobility_area).popcnt() as usize;
+        score.mobility += params.knight_mobility[mobility];
+        if is_outpost(color, square, own_pawns, enemy_pawns) {
+            score.placement += params.knight_outpost;
+        }
+    }
+
+    for square in board.pieces(Piece::Bishop) & own {
+        let mobility = (get_bishop_moves(square, occupied) & mobility_area).popcnt() as usize;
+        score.mobility += params.bishop_mobility[mobility];
+        if is_outpost(color, square, own_pawns, enemy_pawns) {
+            score.placement += params.bishop_outpost;
+        }
+        score.placement +=
+            params.bad_bishop_pawn * (own_pawns & same_colored_squares(square)).popcnt() as i32;
+        if is_trapped_bishop(color, square, enemy_pawns) {
+            score.placement += params.trapped_bishop;
+        }
+    }
+
+    for square in board.pieces(Piece::Rook) & own {
+        let mobility = (get_rook_moves(square, occupied) & mobility_area).popcnt() as usize;
+        score.mobility += params.rook_mobility[mobility];
+
+        let file = get_file(square.get_file());
+        if pawns & file == EMPTY {
+            score.placement += params.rook_open_file;
+        } else if own_pawns & file == EMPTY {
+            score.placement += params.rook_semi_open_file;
+        }
+
+        // On the seventh rank a rook attacks pawns that have not moved yet and cuts off
+        // the king.
+        if relative_rank(color, square) == 6
+            && (enemy_pawns & chess::get_rank(square.get_rank()) != EMPTY
+                || relative_rank(color, board.king_square(!color)) == 7)
+        {
+            score.placement += params.rook_on_seventh;
+        }
+
+        if mobility <= 3 && is_boxed_in_by_king(color, square, board.king_square(color)) {
+            score.placement += params.trapped_rook;
+        }
+    }
+
+    for square in board.pieces(Piece::Queen) & own {
+        let attacks = get_bishop_moves(square, occupied) | get_rook_moves(square, occupied);
+        score.mobility += params.queen_mobility[(attacks & mobility_area).popcnt() as usize];
+    }
+
+    score
+}
+
+/// A square in the enemy half that is defended by an own pawn and can never be
+/// attacked by an enemy pawn.
+fn is_outpost(color: Color, square: Square, own_pawns: BitBoard, enemy_pawns: BitBoard) -> bool {
+    (3..=5).contains(&relative_rank(color, square))
+        && get_pawn_attacks(square, !color, own_pawns) != EMPTY
+        && enemy_pawns & get_adjacent_files(square.get_file()) & ranks_in_front(color, square)
+            == EMPTY
+}
+
+/// All squares of the color of `square`.
+fn same_colored_squares(square: Square) -> BitBoard {
+    if LIGHT_SQUARES & BitBoard::from_square(square) != EMPTY {
+        LIGHT_SQUARES
+    } else {
+        !LIGHT_SQUARES
+    }
+}
+
+/// A bishop that took the pawn on a7 or h7 and is shut in by a pawn on b6 or g6.
+fn is_trapped_bishop(color: Color, square: Square, enemy_pawns: BitBoard) -> bool {
+    // Flipping the rank turns a square into the same square from the view of black.
+    let flip = match color {
+        Color::White => 0,
+        Color::Black => 56,
+    };
+    let blocker = match square.to_index() ^ flip {
+        48 => 41,
+        55 => 46,
+        _ => return false,
+    };
+    enemy_pawns & BitBoard(1 << (blocker ^ flip)) != EMPTY
+}
+
+/// A rook on the back rank between the corner and its king, which moved there without
+/// castling.
+fn is_boxed_in_by_king(color: Color, rook: Square, king: Square) -> bool {
+    let rook_file = rook.get_file().to_index();
+    let king_file = king.get_file().to_index();
+    relative_rank(color, rook) == 0
+        && relative_rank(color, king) == 0
+        && match king_file {
+            5 | 6 => rook_file > king_file,
+            1..=3 => rook_file < king_file,
+            _ => false,
+        }
+}
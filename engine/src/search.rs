@@ -1,282 +1,1244 @@
-use std::time::{Duration, Instant};
+use std::{
+    cmp::Reverse,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use chess::{Board, BoardStatus, CacheTable, ChessMove, MoveGen};
+use chess::{BoardStatus, ChessMove, Piece};
 use tokio::sync::{mpsc::UnboundedSender, watch::Receiver};
-use vampirc_uci::{UciInfoAttribute, UciMessage};
+use vampirc_uci::{UciInfoAttribute, UciMessage, UciSearchControl};
 
-use crate::{eval, game::Game};
+use crate::{
+    eval,
+    game::Game,
+    move_picker::{self, HistoryTable, KillerMoves, MovePicker},
+    params::EvalParams,
+    pawns::PawnHashTable,
+    see,
+    time_broker::{SearchSignals, TimeManager},
+    transposition_table::{AlphaBetaFlag, CacheEntry, TranspositionTable},
+};
 
-pub struct SearchInfo {
+/// One of the principal variations found by the search.
+#[derive(Clone, Debug)]
+pub struct PvLine {
     pub score: i32,
     pub pv: Vec<ChessMove>,
-    pub nodes: u32,
-    pub nps: u32,
+}
+
+pub struct SearchInfo {
+    /// Best lines ranked from best to worst, more than one in MultiPV mode.
+    pub lines: Vec<PvLine>,
+    pub nodes: u64,
+    pub nps: u64,
     pub depth: usize,
     pub time: Duration,
 }
 
-#[derive(PartialEq, PartialOrd, Clone, Copy, Debug)]
-pub enum AlphaBetaFlag {
-    Exact,
-    LowerBound,
-    UpperBound,
+impl SearchInfo {
+    /// Score of the best line.
+    pub fn score(&self) -> i32 {
+        self.lines.first().map_or(0, |line| line.score)
+    }
+
+    /// Principal variation of the best line.
+    pub fn pv(&self) -> &[ChessMove] {
+        self.lines.first().map_or(&[], |line| &line.pv)
+    }
+}
+
+/// Engine settings that stay the same between searches.
+#[derive(Clone, Debug)]
+pub struct SearchOptions {
+    /// Number of search threads.
+    pub threads: usize,
+    /// Number of best lines to search and report.
+    pub multi_pv: usize,
+    pub reverse_futility_pruning: bool,
+    pub futility_pruning: bool,
+    pub razoring: bool,
+    pub probcut: bool,
+    /// Weights of the evaluation.
+    pub eval_params: Arc<EvalParams>,
 }
 
-impl Default for AlphaBetaFlag {
+impl Default for SearchOptions {
     fn default() -> Self {
-        AlphaBetaFlag::LowerBound
+        SearchOptions {
+            threads: 1,
+            multi_pv: 1,
+            reverse_futility_pruning: true,
+            futility_pruning: true,
+            razoring: true,
+            probcut: true,
+            eval_params: Arc::new(EvalParams::default()),
+        }
     }
 }
 
-#[derive(PartialEq, PartialOrd, Clone, Copy, Debug, Default)]
-pub struct CacheEntry {
-    pub chess_move: ChessMove,
-    pub depth: usize,
-    pub flag: AlphaBetaFlag,
-    pub value: i32,
+/// Deepest ply a mate score can be found at, scores beyond it are mate scores.
+const MAX_MATE_PLY: i32 = 256;
+pub(crate) const MATE_THRESHOLD: i32 = eval::MAX_CP_SCORE - MAX_MATE_PLY;
+
+/// Check if a score encodes a forced mate for either side.
+pub fn is_mate_score(score: i32) -> bool {
+    score.abs() >= MATE_THRESHOLD
 }
 
+/// Convert a mate score into full moves until mate, negative if we get mated.
+pub fn mate_in_moves(score: i32) -> i32 {
+    if score > 0 {
+        (eval::MAX_CP_SCORE - score + 1) / 2
+    } else {
+        -(eval::MAX_CP_SCORE + score) / 2
+    }
+}
+
+/// Mate scores are stored relative to the cached position instead of the root, so
+/// they stay correct when the position is reached at a different ply.
+fn score_to_cache(score: i32, ply: usize) -> i32 {
+    if score >= MATE_THRESHOLD {
+        score + ply as i32
+    } else if score <= -MATE_THRESHOLD {
+        score - ply as i32
+    } else {
+        score
+    }
+}
+
+fn score_from_cache(score: i32, ply: usize) -> i32 {
+    if score >= MATE_THRESHOLD {
+        score - ply as i32
+    } else if score <= -MATE_THRESHOLD {
+        score + ply as i32
+    } else {
+        score
+    }
+}
+
+/// Score for UCI info, `bound` tells whether it is only a lower or upper bound.
+fn score_info_attribute(score: i32, bound: AlphaBetaFlag) -> UciInfoAttribute {
+    let (cp, mate) = if is_mate_score(score) {
+        (
+            None,
+            Some(mate_in_moves(score).clamp(i8::MIN as i32, i8::MAX as i32) as i8),
+        )
+    } else {
+        (Some(score), None)
+    };
+    UciInfoAttribute::Score {
+        cp,
+        mate,
+        lower_bound: (bound == AlphaBetaFlag::LowerBound).then_some(true),
+        upper_bound: (bound == AlphaBetaFlag::UpperBound).then_some(true),
+    }
+}
+
+/// Deepest iteration of the iterative deepening, so searches of positions without
+/// any legal moves terminate.
+const MAX_DEPTH: usize = 128;
+
+/// Half width of the first aspiration window in cp.
+const ASPIRATION_WINDOW: i32 = 25;
+/// Shallower iterations are cheap and their scores unstable, they use a full window.
+const MIN_ASPIRATION_DEPTH: usize = 5;
+
+/// Shallowest depth at which a null move is tried.
+const NULL_MOVE_MIN_DEPTH: usize = 3;
+/// Depth reduction of the null move search, before adjusting for depth and eval.
+const NULL_MOVE_BASE_REDUCTION: usize = 3;
+/// Eval above beta (in cp) for every additional ply of null move reduction.
+const NULL_MOVE_EVAL_MARGIN: i32 = 200;
+/// Null move cutoffs from this depth on are verified with a reduced normal search.
+const NULL_MOVE_VERIFICATION_DEPTH: usize = 10;
+
+/// Internal iterative reduction is applied from this depth on.
+const IIR_MIN_DEPTH: usize = 4;
+/// Singular extensions are only tried from this depth on.
+const SINGULAR_MIN_DEPTH: usize = 8;
+/// A cached value is used for singular extensions if it was searched at most this many
+/// plies shallower.
+const SINGULAR_MAX_DEPTH_DIFFERENCE: usize = 3;
+/// Margin (in cp) per ply of depth by which all other moves have to be worse than the
+/// hash move for it to be singular.
+const SINGULAR_MARGIN: i32 = 2;
+
+/// Reverse futility pruning is only applied up to this depth.
+const REVERSE_FUTILITY_MAX_DEPTH: usize = 6;
+/// Margin (in cp) per ply of depth by which the static eval has to exceed beta for
+/// reverse futility pruning.
+const REVERSE_FUTILITY_MARGIN: i32 = 80;
+/// Futility pruning of quiet moves is only applied up to this depth.
+const FUTILITY_MAX_DEPTH: usize = 6;
+/// Margin (in cp) per ply of depth a quiet move is assumed to gain at most.
+const FUTILITY_MARGIN: i32 = 100;
+/// Razoring is only applied up to this depth.
+const RAZORING_MAX_DEPTH: usize = 2;
+/// Margin (in cp) per ply of depth by which the static eval has to be below alpha for
+/// razoring.
+const RAZORING_MARGIN: i32 = 300;
+/// ProbCut is only tried from this depth on.
+const PROBCUT_MIN_DEPTH: usize = 5;
+/// Margin (in cp) above beta a capture has to reach in the shallow ProbCut search.
+const PROBCUT_MARGIN: i32 = 200;
+/// Depth reduction of the ProbCut search.
+const PROBCUT_REDUCTION: usize = 4;
+
+/// Captures are pruned by static exchange evaluation only up to this depth.
+const SEE_PRUNING_MAX_DEPTH: usize = 3;
+/// Material (in cp) per ply of depth a capture may lose before it is pruned.
+const SEE_PRUNING_MARGIN: i32 = 100;
+
+/// Safety margin (in cp) of delta pruning in the quiescence search, for positional gains
+/// of a capture.
+const DELTA_MARGIN: i32 = 200;
+
+/// Late move reductions are only applied from this depth on.
+const LMR_MIN_DEPTH: usize = 3;
+/// Number of moves searched at full depth before late move reductions start.
+const LMR_MIN_MOVES: usize = 3;
+/// Late move pruning is only applied up to this depth.
+const LATE_MOVE_PRUNING_MAX_DEPTH: usize = 3;
+/// Late move pruning starts after this many quiet moves plus the depth squared.
+const LATE_MOVE_PRUNING_BASE: usize = 3;
+
+/// Depth reduction of a late quiet move, growing with the depth and the number of moves
+/// searched before it.
+fn late_move_reduction(depth_left: usize, moves_searched: usize) -> usize {
+    (0.75 + (depth_left as f64).ln() * (moves_searched as f64).ln() / 2.25) as usize
+}
+
+/// Limits of a search besides the time control.
+#[derive(Clone, Debug, Default)]
+pub struct SearchLimits {
+    /// Maximum depth in plies.
+    pub depth: Option<usize>,
+    /// Maximum number of nodes, checked during the search.
+    pub nodes: Option<u64>,
+    /// Stop as soon as a mate in this many moves (or less) is found.
+    pub mate: Option<u32>,
+    /// Only consider these moves at the root, all legal moves if empty.
+    pub search_moves: Vec<ChessMove>,
+    /// The caller waits for `stop` before reporting the result.
+    pub infinite: bool,
+}
+
+impl SearchLimits {
+    pub fn from_search_control(search_control: &UciSearchControl) -> SearchLimits {
+        SearchLimits {
+            depth: search_control.depth.map(|d| d as usize),
+            nodes: search_control.nodes,
+            mate: search_control.mate.map(|m| m as u32),
+            search_moves: search_control.search_moves.clone(),
+            infinite: false,
+        }
+    }
+
+    fn is_mate_found(&self, score: i32) -> bool {
+        matches!(self.mate, Some(mate)
+            if is_mate_score(score) && (1..=mate as i32).contains(&mate_in_moves(score)))
+    }
+}
+
+/// Node counter of one search thread, on its own cache line to avoid false sharing.
+#[derive(Default)]
+#[repr(align(64))]
+struct NodeCounter(AtomicU64);
+
+/// State shared by all threads of one search.
+struct SharedState<'a> {
+    cache: &'a TranspositionTable,
+    limits: &'a SearchLimits,
+    options: &'a SearchOptions,
+    /// Set when the main thread is done, to stop the helper threads.
+    stop: AtomicBool,
+    node_counters: Vec<NodeCounter>,
+}
+
+impl SharedState<'_> {
+    fn nodes(&self) -> u64 {
+        self.node_counters
+            .iter()
+            .map(|counter| counter.0.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+/// State of one search thread, shared by all its nodes.
+struct SearchContext<'a> {
+    shared: &'a SharedState<'a>,
+    thread_index: usize,
+    cancel_receiver: Receiver<bool>,
+    /// Root moves of the lines already found in this iteration, for MultiPV.
+    excluded_root_moves: Vec<ChessMove>,
+    nodes: u64,
+    cancelled: bool,
+    /// Stopping is only allowed once the first iteration finished, so there always
+    /// is a move to report.
+    can_stop: bool,
+    quiescence_search_depth: usize,
+    killers: KillerMoves,
+    history: HistoryTable,
+    pawn_table: PawnHashTable,
+    /// Null moves are not tried before this ply, set by the null-move verification.
+    null_move_min_ply: usize,
+    /// Move skipped by the next node, set by the singular extension search.
+    excluded_move: Option<ChessMove>,
+    /// Depth of the current iteration, the budget for extensions.
+    root_depth: usize,
+}
+
+/// Search the position with `options.threads` threads in parallel (Lazy SMP).
+///
+/// All threads run their own iterative deepening and only communicate through the
+/// transposition table. The helper threads fill it with results that let the main
+/// thread search faster; only the main thread reports and decides when to stop.
 pub fn iterative_deepening(
     game: &Game,
-    max_depth: Option<usize>,
-    cancel_receiver: Receiver<bool>,
+    limits: &SearchLimits,
+    signals: SearchSignals,
+    cache: &mut TranspositionTable,
+    options: &SearchOptions,
     output: &UnboundedSender<UciMessage>,
 ) -> SearchInfo {
     let time = Instant::now();
-    let mut cache = CacheTable::<CacheEntry>::new(256 * 1024 * 1024, CacheEntry::default());
-
-    let mut depth = 0;
-    let mut result = SearchInfo {
-        score: 0,
-        pv: vec![],
-        nodes: 0,
-        nps: 0,
-        depth: 1,
-        time: Duration::ZERO,
+    cache.new_search();
+    let shared = SharedState {
+        cache,
+        limits,
+        options,
+        stop: AtomicBool::new(false),
+        node_counters: (0..options.threads.max(1))
+            .map(|_| NodeCounter::default())
+            .collect(),
     };
 
-    loop {
-        depth += 1;
-
-        if max_depth.map_or_else(|| false, |md| depth > md) {
-            break;
+    let mut result = thread::scope(|scope| {
+        for thread_index in 1..options.threads {
+            let mut helper = SearchContext::new(&shared, thread_index, signals.cancel.clone());
+            scope.spawn(move || helper.helper_iterative_deepening(game));
         }
 
-        let (score, moves, nodes, cancelled) = alphabeta(
-            game,
-            -eval::MAX_CP_SCORE,
-            eval::MAX_CP_SCORE,
-            &cancel_receiver,
-            depth,
-            0,
-            (depth / 2).min(8),
-            &mut cache,
-        );
+        let mut main = SearchContext::new(&shared, 0, signals.cancel);
+        let result = main.main_iterative_deepening(game, signals.time_manager, time, output);
+        shared.stop.store(true, Ordering::Relaxed);
+        result
+    });
+
+    result.nodes = shared.nodes();
+    result.nps = (result.nodes as f64 / time.elapsed().as_secs_f64()) as u64;
+    result.time = time.elapsed();
+
+    let mut attributes = vec![score_info_attribute(result.score(), AlphaBetaFlag::Exact)];
+    if !result.pv().is_empty() {
+        attributes.push(UciInfoAttribute::Pv(result.pv().to_vec()));
+    }
+    attributes.extend([
+        UciInfoAttribute::Nodes(result.nodes),
+        UciInfoAttribute::Nps(result.nps),
+        UciInfoAttribute::HashFull(shared.cache.hashfull()),
+        UciInfoAttribute::Depth(result.depth.try_into().unwrap()),
+        UciInfoAttribute::Time(
+            vampirc_uci::Duration::from_std(result.time).unwrap_or(vampirc_uci::Duration::zero()),
+        ),
+    ]);
+    output.send(UciMessage::Info(attributes)).unwrap();
 
-        if cancelled {
-            break;
+    result
+}
+
+impl<'a> SearchContext<'a> {
+    fn new(
+        shared: &'a SharedState<'a>,
+        thread_index: usize,
+        cancel_receiver: Receiver<bool>,
+    ) -> SearchContext<'a> {
+        SearchContext {
+            shared,
+            thread_index,
+            cancel_receiver,
+            excluded_root_moves: Vec::new(),
+            nodes: 0,
+            cancelled: false,
+            // Helper threads have no result to report.
+            can_stop: thread_index != 0,
+            quiescence_search_depth: 0,
+            killers: KillerMoves::new(MAX_DEPTH),
+            history: HistoryTable::new(),
+            pawn_table: PawnHashTable::new(),
+            null_move_min_ply: 0,
+            excluded_move: None,
+            root_depth: 0,
         }
+    }
 
-        result.score = score;
-        result.pv = moves;
-        result.nodes += nodes as u32;
-        result.depth = depth;
+    fn main_iterative_deepening(
+        &mut self,
+        game: &Game,
+        mut time_manager_receiver: Receiver<Option<TimeManager>>,
+        time: Instant,
+        output: &UnboundedSender<UciMessage>,
+    ) -> SearchInfo {
+        let limits = self.shared.limits;
+        let mut time_manager: Option<TimeManager> = None;
+        let max_depth = limits.depth.map_or(MAX_DEPTH, |d| d.min(MAX_DEPTH));
 
-        let answer = UciMessage::Info {
-            0: vec![
-                UciInfoAttribute::Score {
-                    cp: Some(result.score),
-                    mate: None,
-                    lower_bound: None,
-                    upper_bound: None,
-                },
-                UciInfoAttribute::Pv(result.pv.clone()),
-                UciInfoAttribute::Nodes(result.nodes.try_into().unwrap()),
-                UciInfoAttribute::Depth(result.depth.try_into().unwrap()),
-                UciInfoAttribute::Time(
-                    vampirc_uci::Duration::from_std(time.elapsed())
-                        .unwrap_or(vampirc_uci::Duration::zero()),
-                ),
-            ],
+        let mut depth = 0;
+        let mut result = SearchInfo {
+            lines: vec![],
+            nodes: 0,
+            nps: 0,
+            depth: 1,
+            time: Duration::ZERO,
         };
-        output.send(answer).unwrap();
+
+        loop {
+            depth += 1;
+
+            if depth > max_depth {
+                break;
+            }
+
+            self.root_depth = depth;
+            self.quiescence_search_depth = (depth / 2).min(8);
+
+            // Every further line is the best line without the root moves found before.
+            let mut lines = Vec::with_capacity(self.shared.options.multi_pv);
+            self.excluded_root_moves.clear();
+            while lines.len() < self.shared.options.multi_pv {
+                let previous_score = result.lines.get(lines.len()).map(|line| line.score);
+                let (score, moves) = self.aspiration_search(
+                    game,
+                    depth,
+                    previous_score,
+                    lines.len() + 1,
+                    time,
+                    output,
+                );
+
+                if self.cancelled || moves.is_empty() {
+                    break;
+                }
+                self.excluded_root_moves.push(moves[0]);
+                lines.push(PvLine { score, pv: moves });
+            }
+
+            if self.cancelled {
+                break;
+            }
+            self.can_stop = true;
+
+            lines.sort_by_key(|line| Reverse(line.score));
+            result.lines = lines;
+            result.nodes = self.shared.nodes();
+            result.depth = depth;
+
+            for (index, line) in result.lines.iter().enumerate() {
+                self.send_line_info(
+                    output,
+                    index + 1,
+                    line.score,
+                    AlphaBetaFlag::Exact,
+                    &line.pv,
+                    depth,
+                    time,
+                );
+            }
+
+            if limits.is_mate_found(result.score()) {
+                break;
+            }
+
+            // The clock of a pondering search starts with `ponderhit`.
+            if time_manager_receiver.has_changed().unwrap_or(false) {
+                time_manager = time_manager_receiver.borrow_and_update().clone();
+            }
+            if let Some(tm) = time_manager.as_mut() {
+                if !tm.should_start_iteration(&result) {
+                    break;
+                }
+            }
+        }
+
+        result
     }
 
-    result.nps = (result.nodes as f64 / time.elapsed().as_secs_f64()) as u32;
-    result.time = time.elapsed();
+    /// Search the root with a window around the score of the previous iteration, which
+    /// cuts off more as long as the score does not change much.
+    ///
+    /// A search that fails is reported as bound and repeated with a wider window.
+    fn aspiration_search(
+        &mut self,
+        game: &Game,
+        depth: usize,
+        previous_score: Option<i32>,
+        multi_pv: usize,
+        time: Instant,
+        output: &UnboundedSender<UciMessage>,
+    ) -> (i32, Vec<ChessMove>) {
+        let mut delta = ASPIRATION_WINDOW;
+        let (mut alpha, mut beta) = match previous_score {
+            Some(score) if depth >= MIN_ASPIRATION_DEPTH && !is_mate_score(score) => (
+                (score - delta).max(-eval::MAX_CP_SCORE),
+                (score + delta).min(eval::MAX_CP_SCORE),
+            ),
+            _ => (-eval::MAX_CP_SCORE, eval::MAX_CP_SCORE),
+        };
+
+        loop {
+            let (score, moves) = self.alphabeta(game, alpha, beta, depth, 0);
 
-    let answer = UciMessage::Info {
-        0: vec![
-            UciInfoAttribute::Score {
-                cp: Some(result.score),
-                mate: None,
-                lower_bound: None,
-                upper_bound: None,
-            },
-            UciInfoAttribute::Pv(result.pv.clone()),
-            UciInfoAttribute::Nodes(result.nodes.try_into().unwrap()),
-            UciInfoAttribute::Nps(result.nps.try_into().unwrap()),
-            UciInfoAttribute::Depth(result.depth.try_into().unwrap()),
+            if self.cancelled {
+                return (score, moves);
+            }
+
+            delta *= 2;
+            if score <= alpha && alpha > -eval::MAX_CP_SCORE {
+                // Without a move better than alpha there is no PV to report.
+                self.send_line_info(
+                    output,
+                    multi_pv,
+                    score,
+                    AlphaBetaFlag::UpperBound,
+                    &[],
+                    depth,
+                    time,
+                );
+                alpha = (score - delta).max(-eval::MAX_CP_SCORE);
+            } else if score >= beta && beta < eval::MAX_CP_SCORE {
+                self.send_line_info(
+                    output,
+                    multi_pv,
+                    score,
+                    AlphaBetaFlag::LowerBound,
+                    &moves,
+                    depth,
+                    time,
+                );
+                beta = (score + delta).min(eval::MAX_CP_SCORE);
+            } else {
+                return (score, moves);
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn send_line_info(
+        &self,
+        output: &UnboundedSender<UciMessage>,
+        multi_pv: usize,
+        score: i32,
+        bound: AlphaBetaFlag,
+        pv: &[ChessMove],
+        depth: usize,
+        time: Instant,
+    ) {
+        let mut attributes = vec![
+            UciInfoAttribute::MultiPv(multi_pv.try_into().unwrap()),
+            score_info_attribute(score, bound),
+        ];
+        if !pv.is_empty() {
+            attributes.push(UciInfoAttribute::Pv(pv.to_vec()));
+        }
+        attributes.extend([
+            UciInfoAttribute::Nodes(self.shared.nodes()),
+            UciInfoAttribute::HashFull(self.shared.cache.hashfull()),
+            UciInfoAttribute::Depth(depth.try_into().unwrap()),
             UciInfoAttribute::Time(
-                vampirc_uci::Duration::from_std(result.time)
+                vampirc_uci::Duration::from_std(time.elapsed())
                     .unwrap_or(vampirc_uci::Duration::zero()),
             ),
-        ],
-    };
-    output.send(answer).unwrap();
+        ]);
+        output.send(UciMessage::Info(attributes)).unwrap();
+    }
 
-    result
-}
+    fn helper_iterative_deepening(&mut self, game: &Game) {
+        let max_depth = self
+            .shared
+            .limits
+            .depth
+            .map_or(MAX_DEPTH, |d| d.min(MAX_DEPTH));
 
-pub fn alphabeta(
-    game: &Game,
-    mut alpha: i32,
-    mut beta: i32,
-    cancel_receiver: &Receiver<bool>,
-    depth_left: usize,
-    ply: usize,
-    quiescence_search_depth: usize,
-    cache: &mut CacheTable<CacheEntry>,
-) -> (i32, Vec<ChessMove>, i32, bool) {
-    let original_alpha = alpha;
-    let mut best_score = -eval::MAX_CP_SCORE;
-    let mut best_pricipal_variation = Vec::<ChessMove>::new();
-    let mut total_leaves_searched = 0;
-
-    if let Some(entry) = cache.get(game.position().get_hash()) {
-        if entry.depth as usize >= depth_left {
-            match entry.flag {
-                AlphaBetaFlag::Exact => {
+        // Every second helper runs one ply ahead, so the threads diverge more.
+        let mut depth = self.thread_index % 2;
+        loop {
+            depth += 1;
+
+            if depth > max_depth {
+                break;
+            }
+
+            self.root_depth = depth;
+            self.quiescence_search_depth = (depth / 2).min(8);
+            self.alphabeta(game, -eval::MAX_CP_SCORE, eval::MAX_CP_SCORE, depth, 0);
+
+            if self.cancelled {
+                break;
+            }
+        }
+    }
+
+    fn count_node(&mut self) {
+        self.nodes += 1;
+        self.shared.node_counters[self.thread_index]
+            .0
+            .store(self.nodes, Ordering::Relaxed);
+    }
+
+    /// Check all stop conditions and latch the result in `cancelled`.
+    fn should_stop(&mut self) -> bool {
+        if !self.cancelled && self.can_stop {
+            self.cancelled = self.shared.stop.load(Ordering::Relaxed)
+                || matches!(self.shared.limits.nodes, Some(n) if self.shared.nodes() >= n)
+                || (self.cancel_receiver.has_changed().unwrap_or(true)
+                    && *self.cancel_receiver.borrow());
+        }
+        self.cancelled
+    }
+
+    fn alphabeta(
+        &mut self,
+        game: &Game,
+        mut alpha: i32,
+        mut beta: i32,
+        mut depth_left: usize,
+        ply: usize,
+    ) -> (i32, Vec<ChessMove>) {
+        // Only applies to this node, not to its children.
+        let excluded_move = self.excluded_move.take();
+        let mut best_score = -eval::MAX_CP_SCORE;
+        let mut best_pricipal_variation = Vec::<ChessMove>::new();
+        self.count_node();
+
+        // Draws depend on the path to the position, so they have to be detected before
+        // the cache is consulted.
+        if ply > 0
+            && (game.is_repetition(ply)
+                || (game.is_fifty_move_draw()
+                    && game.position().status() != BoardStatus::Checkmate))
+        {
+            return (0, Vec::new());
+        }
+
+        // Mate distance pruning: even mating right here cannot beat a shorter mate that
+        // was already found closer to the root.
+        if ply > 0 {
+            alpha = alpha.max(-eval::MAX_CP_SCORE + ply as i32);
+            beta = beta.min(eval::MAX_CP_SCORE - ply as i32 - 1);
+            if alpha >= beta {
+                return (alpha, Vec::new());
+            }
+        }
+        let original_alpha = alpha;
+        let is_pv_node = beta - alpha > 1;
+
+        // The move check catches entries of positions with a colliding key.
+        let cache_entry = self
+            .shared
+            .cache
+            .get(game.position().get_hash())
+            .filter(|entry| game.position().legal(entry.chess_move));
+
+        // The root always searches its moves, a cutoff would leave no proper PV and
+        // could return a move excluded by `searchmoves`. The same holds for every PV
+        // node, a cutoff there cuts the reported line short, and entries stored by the
+        // helper threads would replace it. The entry of a search with an excluded move
+        // is the result of the full search.
+        if let Some(entry) =
+            cache_entry.filter(|_| ply > 0 && !is_pv_node && excluded_move.is_none())
+        {
+            if entry.depth >= depth_left {
+                let value = score_from_cache(entry.value, ply);
+                match entry.flag {
+                    AlphaBetaFlag::Exact => {
+                        best_pricipal_variation.push(entry.chess_move);
+                        return (value, best_pricipal_variation);
+                    }
+                    AlphaBetaFlag::LowerBound => {
+                        if value > alpha {
+                            alpha = value;
+                        };
+                    }
+                    AlphaBetaFlag::UpperBound => {
+                        if value < beta {
+                            beta = value;
+                        };
+                    }
+                }
+                if alpha >= beta {
                     best_pricipal_variation.push(entry.chess_move);
-                    return (entry.value, best_pricipal_variation, 1, false);
+                    return (value, best_pricipal_variation);
                 }
-                AlphaBetaFlag::LowerBound => {
-                    if entry.value > alpha {
-                        alpha = entry.value;
-                    };
+            }
+        }
+
+        match game.position().status() {
+            BoardStatus::Stalemate => {
+                return (0, Vec::new());
+            }
+            BoardStatus::Checkmate => {
+                return (-eval::MAX_CP_SCORE + ply as i32, Vec::new());
+            }
+            _ => {}
+        }
+
+        if depth_left == 0 {
+            let score =
+                self.quiescence_search(game, alpha, beta, self.quiescence_search_depth, ply);
+            return (score, Vec::new());
+        }
+
+        let in_check = game.position().checkers().popcnt() > 0;
+
+        // Forward pruning trusts the static evaluation, which is only sound in quiet
+        // nodes that are not on the principal variation.
+        let static_eval =
+            (!is_pv_node && !in_check && excluded_move.is_none() && ply > 0).then(|| {
+                eval::evaluate_position(
+                    game.position(),
+                    &self.shared.options.eval_params,
+                    &mut self.pawn_table,
+                )
+            });
+
+        if let Some(static_eval) = static_eval {
+            if let Some(score) = self.forward_pruning(game, beta, depth_left, ply, static_eval) {
+                return (score, Vec::new());
+            }
+        }
+
+        // Internal iterative reduction: without a hash move the ordering is poor and the
+        // node likely unimportant, it is searched one ply shallower.
+        if cache_entry.is_none() && depth_left >= IIR_MIN_DEPTH {
+            depth_left -= 1;
+        }
+
+        let singular_move = match cache_entry {
+            Some(entry) if excluded_move.is_none() => {
+                self.singular_move(game, entry, depth_left, ply)
+            }
+            _ => None,
+        };
+
+        let mut move_picker = MovePicker::new(
+            game.position(),
+            cache_entry
+                .map(|entry| entry.chess_move)
+                .filter(|&mv| Some(mv) != excluded_move),
+            self.killers.get(ply),
+        );
+        let mut quiets_tried = Vec::new();
+        let mut moves_searched = 0;
+
+        while let Some(mv) = move_picker.next(&self.history) {
+            if Some(mv) == excluded_move {
+                continue;
+            }
+            if ply == 0
+                && (self.excluded_root_moves.contains(&mv)
+                    || (!self.shared.limits.search_moves.is_empty()
+                        && !self.shared.limits.search_moves.contains(&mv)))
+            {
+                continue;
+            }
+
+            if self.should_stop() {
+                return (0, Vec::new());
+            }
+
+            let new_game = game.make_move_new(mv);
+            let is_quiet = !move_picker::is_noisy(game.position(), mv);
+            let gives_check = new_game.position().checkers().popcnt() > 0;
+            let is_late_quiet = is_quiet && !in_check && !gives_check;
+
+            // Late move pruning: with good move ordering, quiet moves that come this late
+            // at shallow depth hardly ever raise alpha.
+            if is_late_quiet
+                && !is_pv_node
+                && depth_left <= LATE_MOVE_PRUNING_MAX_DEPTH
+                && quiets_tried.len() >= LATE_MOVE_PRUNING_BASE + depth_left * depth_left
+                && best_score > -MATE_THRESHOLD
+            {
+                continue;
+            }
+
+            // Futility pruning: close to the leaves a quiet move cannot make up for a
+            // static evaluation far below alpha.
+            if let Some(static_eval) = static_eval {
+                if self.shared.options.futility_pruning
+                    && is_late_quiet
+                    && depth_left <= FUTILITY_MAX_DEPTH
+                    && best_score > -MATE_THRESHOLD
+                    && static_eval + FUTILITY_MARGIN * (depth_left as i32 + 1) <= alpha
+                {
+                    continue;
                 }
-                AlphaBetaFlag::UpperBound => {
-                    if entry.value < beta {
-                        beta = entry.value;
-                    };
+            }
+
+            // At shallow depth captures that lose much material hardly ever raise alpha.
+            if !is_quiet
+                && !is_pv_node
+                && !in_check
+                && !gives_check
+                && depth_left <= SEE_PRUNING_MAX_DEPTH
+                && best_score > -MATE_THRESHOLD
+                && see::see(game.position(), mv) < -SEE_PRUNING_MARGIN * depth_left as i32
+            {
+                continue;
+            }
+
+            self.shared.cache.prefetch(new_game.position().get_hash());
+
+            // Extend checks and the singular move, as long as the budget allows.
+            let extension =
+                usize::from(self.can_extend(ply) && (gives_check || Some(mv) == singular_move));
+            let new_depth = depth_left - 1 + extension;
+
+            // Late move reductions: late quiet moves are searched shallower first and only
+            // at full depth if they turn out better than expected.
+            let reduction = if is_late_quiet
+                && depth_left >= LMR_MIN_DEPTH
+                && moves_searched >= LMR_MIN_MOVES
+            {
+                let mut reduction = late_move_reduction(depth_left, moves_searched);
+                if is_pv_node || self.killers.get(ply).contains(&Some(mv)) {
+                    reduction = reduction.saturating_sub(1);
                 }
+                reduction.min(new_depth.saturating_sub(1))
+            } else {
+                0
+            };
+
+            // Principal variation search: the first move is expected to be best, later
+            // moves only have to be proven worse with a null window.
+            let (mut new_score, mut new_moves) = if moves_searched == 0 {
+                self.alphabeta(&new_game, -beta, -alpha, new_depth, ply + 1)
+            } else {
+                self.alphabeta(
+                    &new_game,
+                    -alpha - 1,
+                    -alpha,
+                    new_depth - reduction,
+                    ply + 1,
+                )
+            };
+            new_score = -new_score;
+            if reduction > 0 && new_score > alpha && !self.cancelled {
+                let (score, moves) =
+                    self.alphabeta(&new_game, -alpha - 1, -alpha, new_depth, ply + 1);
+                new_score = -score;
+                new_moves = moves;
             }
+            if moves_searched > 0 && new_score > alpha && new_score < beta && !self.cancelled {
+                let (score, moves) = self.alphabeta(&new_game, -beta, -alpha, new_depth, ply + 1);
+                new_score = -score;
+                new_moves = moves;
+            }
+            moves_searched += 1;
+
+            if self.cancelled {
+                return (0, Vec::new());
+            }
+
+            if new_score > best_score {
+                best_pricipal_variation = new_moves;
+                best_pricipal_variation.insert(0, mv);
+                best_score = new_score;
+            }
+
+            if new_score > alpha {
+                alpha = new_score;
+            }
+
             if alpha >= beta {
-                best_pricipal_variation.push(entry.chess_move);
-                return (entry.value, best_pricipal_variation, 1, false);
+                if is_quiet {
+                    self.killers.add(ply, mv);
+                    self.history.update(
+                        game.position().side_to_move(),
+                        mv,
+                        &quiets_tried,
+                        depth_left,
+                    );
+                }
+                break;
+            }
+            if is_quiet {
+                quiets_tried.push(mv);
             }
         }
+
+        // Without a move all root moves were excluded by `searchmoves`. With excluded
+        // moves the score is not the score of the position.
+        if let Some(&chess_move) = best_pricipal_variation
+            .first()
+            .filter(|_| excluded_move.is_none() && (ply > 0 || self.excluded_root_moves.is_empty()))
+        {
+            let entry = CacheEntry {
+                value: score_to_cache(alpha, ply),
+                depth: depth_left,
+                chess_move,
+                flag: if alpha <= original_alpha {
+                    AlphaBetaFlag::UpperBound
+                } else if alpha >= beta {
+                    AlphaBetaFlag::LowerBound
+                } else {
+                    AlphaBetaFlag::Exact
+                },
+            };
+            self.shared.cache.add(game.position().get_hash(), entry);
+        }
+
+        (alpha, best_pricipal_variation)
+    }
+
+    /// Extensions are limited to the first plies of twice the iteration depth, so
+    /// forcing lines cannot extend the search indefinitely.
+    fn can_extend(&self, ply: usize) -> bool {
+        ply < 2 * self.root_depth && ply < MAX_DEPTH
+    }
+
+    /// Singular extension: check if the hash move is much better than all other moves,
+    /// by searching them with a reduced depth and a window below the cached value.
+    ///
+    /// Returns the hash move if it is singular and should be extended.
+    fn singular_move(
+        &mut self,
+        game: &Game,
+        entry: CacheEntry,
+        depth_left: usize,
+        ply: usize,
+    ) -> Option<ChessMove> {
+        let value = score_from_cache(entry.value, ply);
+        if ply == 0
+            || depth_left < SINGULAR_MIN_DEPTH
+            || entry.depth + SINGULAR_MAX_DEPTH_DIFFERENCE < depth_left
+            || entry.flag == AlphaBetaFlag::UpperBound
+            || is_mate_score(value)
+            || !self.can_extend(ply)
+        {
+            return None;
+        }
+
+        let singular_beta = value - SINGULAR_MARGIN * depth_left as i32;
+        self.excluded_move = Some(entry.chess_move);
+        let (score, _) = self.alphabeta(
+            game,
+            singular_beta - 1,
+            singular_beta,
+            (depth_left - 1) / 2,
+            ply,
+        );
+
+        (!self.cancelled && score < singular_beta).then_some(entry.chess_move)
     }
 
-    match game.position().status() {
-        BoardStatus::Stalemate => {
-            return (0, Vec::new(), 1, false);
+    /// Try to prove with the static evaluation or a reduced search that a quiet non-PV
+    /// node fails high or low without searching it.
+    ///
+    /// Returns the score of the node if it can be pruned.
+    fn forward_pruning(
+        &mut self,
+        game: &Game,
+        beta: i32,
+        depth_left: usize,
+        ply: usize,
+        static_eval: i32,
+    ) -> Option<i32> {
+        let options = self.shared.options;
+        let alpha = beta - 1;
+
+        // Reverse futility pruning: the static evaluation is so far above beta that the
+        // opponent will not be able to catch up in the remaining plies.
+        if options.reverse_futility_pruning
+            && depth_left <= REVERSE_FUTILITY_MAX_DEPTH
+            && !is_mate_score(beta)
+            && static_eval - REVERSE_FUTILITY_MARGIN * depth_left as i32 >= beta
+        {
+            return Some(beta);
         }
-        BoardStatus::Checkmate => {
-            return (-eval::MAX_CP_SCORE + ply as i32, Vec::new(), 1, false);
+
+        // Razoring: far below alpha only captures can help, which is verified by the
+        // quiescence search.
+        if options.razoring
+            && depth_left <= RAZORING_MAX_DEPTH
+            && static_eval + RAZORING_MARGIN * depth_left as i32 <= alpha
+        {
+            let score =
+                self.quiescence_search(game, alpha, beta, self.quiescence_search_depth, ply);
+            if self.cancelled || score <= alpha {
+                return Some(score.min(alpha));
+            }
         }
-        _ => {}
+
+        if let Some(score) = self.null_move_pruning(game, beta, depth_left, ply, static_eval) {
+            return Some(score);
+        }
+
+        if options.probcut {
+            return self.probcut(game, beta, depth_left, ply, static_eval);
+        }
+        None
     }
 
-    if depth_left == 0 {
-        let score = quiescence_search(game, alpha, beta, quiescence_search_depth);
-        return (score, Vec::new(), 1, false);
+    /// ProbCut: a good capture that beats beta by a margin in a much shallower search
+    /// will most likely beat beta in the full search as well.
+    fn probcut(
+        &mut self,
+        game: &Game,
+        beta: i32,
+        depth_left: usize,
+        ply: usize,
+        static_eval: i32,
+    ) -> Option<i32> {
+        let probcut_beta = beta + PROBCUT_MARGIN;
+        if depth_left < PROBCUT_MIN_DEPTH || is_mate_score(beta) || is_mate_score(probcut_beta) {
+            return None;
+        }
+
+        let board = game.position();
+        let mut move_picker = MovePicker::new_noisy(board);
+        while let Some(mv) = move_picker.next(&self.history) {
+            // Captures that cannot win enough material on their own are not tried.
+            if static_eval + see::see(board, mv) < probcut_beta {
+                continue;
+            }
+
+            let new_game = game.make_move_new(mv);
+            // A quiescence search first rejects most captures cheaply.
+            let mut score = -self.quiescence_search(
+                &new_game,
+                -probcut_beta,
+                -probcut_beta + 1,
+                self.quiescence_search_depth,
+                ply + 1,
+            );
+            if score >= probcut_beta && !self.cancelled {
+                let (new_score, _) = self.alphabeta(
+                    &new_game,
+                    -probcut_beta,
+                    -probcut_beta + 1,
+                    depth_left - PROBCUT_REDUCTION,
+                    ply + 1,
+                );
+                score = -new_score;
+            }
+
+            if self.cancelled {
+                return None;
+            }
+            if score >= probcut_beta {
+                return Some(score);
+            }
+        }
+        None
     }
 
-    let movegen = MoveGen::new_legal(game.position());
-    let mut cancelled = false;
+    /// Null-move pruning: if the opponent cannot bring the score below beta even when
+    /// the side to move passes, a real move will most likely not either.
+    ///
+    /// Returns the score of a cutoff. The assumption fails in zugzwang, so the pruning is
+    /// left out in pawn endgames and verified with a normal search at high depth.
+    fn null_move_pruning(
+        &mut self,
+        game: &Game,
+        beta: i32,
+        depth_left: usize,
+        ply: usize,
+        static_eval: i32,
+    ) -> Option<i32> {
+        let board = game.position();
+        let own_pieces = board.color_combined(board.side_to_move());
+        let has_non_pawn_material =
+            (own_pieces & !board.pieces(Piece::Pawn) & !board.pieces(Piece::King)).popcnt() > 0;
 
-    for mv in movegen {
-        if cancelled || (cancel_receiver.has_changed().unwrap_or(true) && *cancel_receiver.borrow())
+        if depth_left < NULL_MOVE_MIN_DEPTH
+            || ply < self.null_move_min_ply
+            || game.is_after_null_move()
+            || !has_non_pawn_material
+            || is_mate_score(beta)
         {
-            return (0, Vec::new(), 1, true);
+            return None;
         }
 
-        let new_game = game.make_move_new(mv);
-        let (mut new_score, new_moves, leaves_searched, new_cancelled) = alphabeta(
-            &new_game,
+        if static_eval < beta {
+            return None;
+        }
+
+        // In check there is no null move.
+        let null_game = game.make_null_move_new()?;
+        let reduction = (NULL_MOVE_BASE_REDUCTION
+            + depth_left / 4
+            + ((static_eval - beta) / NULL_MOVE_EVAL_MARGIN).min(3) as usize)
+            .min(depth_left);
+
+        let (score, _) = self.alphabeta(
+            &null_game,
             -beta,
-            -alpha,
-            cancel_receiver,
-            depth_left - 1,
+            -beta + 1,
+            depth_left - reduction,
             ply + 1,
-            quiescence_search_depth,
-            cache,
         );
-        new_score = -new_score;
-        cancelled = cancelled || new_cancelled;
-        total_leaves_searched += leaves_searched;
+        if self.cancelled || -score < beta {
+            return None;
+        }
 
-        if new_score > best_score {
-            best_pricipal_variation = new_moves;
-            best_pricipal_variation.insert(0, mv);
-            best_score = new_score;
+        if depth_left < NULL_MOVE_VERIFICATION_DEPTH {
+            return Some(beta);
         }
 
-        if new_score > alpha {
-            alpha = new_score;
+        // Verify without null moves for the next plies, which catches zugzwang.
+        let null_move_min_ply = self.null_move_min_ply;
+        self.null_move_min_ply = ply + 3 * (depth_left - reduction) / 4;
+        let (score, _) = self.alphabeta(game, beta - 1, beta, depth_left - reduction, ply);
+        self.null_move_min_ply = null_move_min_ply;
+
+        (!self.cancelled && score >= beta).then_some(beta)
+    }
+
+    /// Resolve captures until the position is quiet, so the static evaluation is not
+    /// taken in the middle of an exchange.
+    ///
+    /// The side to move can stand pat with the static evaluation unless in check, then
+    /// all evasions are searched.
+    fn quiescence_search(
+        &mut self,
+        game: &Game,
+        mut alpha: i32,
+        beta: i32,
+        depth_left: usize,
+        ply: usize,
+    ) -> i32 {
+        self.count_node();
+
+        if self.should_stop() {
+            return 0;
         }
 
-        if alpha >= beta {
-            break;
+        let board = game.position();
+        let in_check = board.checkers().popcnt() > 0;
+        let stand_pat = eval::evaluate_position(
+            board,
+            &self.shared.options.eval_params,
+            &mut self.pawn_table,
+        );
+
+        if depth_left == 0 {
+            return stand_pat;
         }
-    }
 
-    if !cancelled {
-        let entry = CacheEntry {
-            value: alpha,
-            depth: depth_left,
-            chess_move: best_pricipal_variation.first().unwrap().clone(),
-            flag: if alpha <= original_alpha {
-                AlphaBetaFlag::UpperBound
-            } else if alpha >= beta {
-                AlphaBetaFlag::LowerBound
-            } else {
-                AlphaBetaFlag::Exact
-            },
+        if !in_check {
+            if stand_pat >= beta {
+                return beta;
+            }
+            if stand_pat > alpha {
+                alpha = stand_pat;
+            }
+        }
+
+        let mut move_picker = if in_check {
+            MovePicker::new(board, None, self.killers.get(ply))
+        } else {
+            MovePicker::new_noisy(board)
         };
-        cache.add(game.position().get_hash(), entry);
-    }
+        let mut has_moves = false;
 
-    (
-        alpha,
-        best_pricipal_variation,
-        total_leaves_searched,
-        cancelled,
-    )
+        while let Some(mv) = move_picker.next(&self.history) {
+            has_moves = true;
+
+            if !in_check {
+                // Delta pruning: even winning the captured piece for free does not
+                // bring the score up to alpha, whichever phase the game is in.
+                let params = &self.shared.options.eval_params;
+                let captured = board
+                    .piece_on(mv.get_dest())
+                    .map_or(params.pawn_mv, |piece| {
+                        eval::get_piece_type_material_value(params, piece)
+                    });
+                let captured_value = captured.mg.max(captured.eg);
+                let promotion_value = mv.get_promotion().map_or(0, |piece| {
+                    let promotion =
+                        eval::get_piece_type_material_value(params, piece) - params.pawn_mv;
+                    promotion.mg.max(promotion.eg)
+                });
+                if stand_pat + captured_value + promotion_value + DELTA_MARGIN < alpha {
+                    continue;
+                }
+            }
+
+            let new_game = game.make_move_new(mv);
+            let new_score =
+                -self.quiescence_search(&new_game, -beta, -alpha, depth_left - 1, ply + 1);
+
+            if self.cancelled {
+                return 0;
+            }
+            if new_score >= beta {
+                return beta;
+            }
+            if new_score > alpha {
+                alpha = new_score;
+            }
+        }
+
+        if in_check && !has_moves {
+            return -eval::MAX_CP_SCORE + ply as i32;
+        }
+        alpha
+    }
 }
 
-fn quiescence_search(game: &Game, mut alpha: i32, beta: i32, depth_left: usize) -> i32 {
-    let score = eval::evaluate_position(game.position());
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if depth_left == 0 {
-        return score;
+    /// Score of the side to move at the root if the side to move at `ply` is mated.
+    fn mated_at(ply: i32) -> i32 {
+        let score = -eval::MAX_CP_SCORE + ply;
+        if ply % 2 == 0 {
+            score
+        } else {
+            -score
+        }
     }
 
-    if score >= beta {
-        return beta;
+    #[test]
+    fn mate_in_one() {
+        assert!(is_mate_score(mated_at(1)));
+        assert_eq!(mate_in_moves(mated_at(1)), 1);
+        assert_eq!(mate_in_moves(mated_at(3)), 2);
     }
 
-    if score > alpha {
-        alpha = score;
+    #[test]
+    fn mated_in_one() {
+        assert!(is_mate_score(mated_at(2)));
+        assert_eq!(mate_in_moves(mated_at(2)), -1);
+        assert_eq!(mate_in_moves(mated_at(4)), -2);
     }
 
-    let mut movegen = MoveGen::new_legal(game.position());
-    let targets = game
-        .position()
-        .color_combined(!game.position().side_to_move());
-    // TODO: Add en_passent here
-    movegen.set_iterator_mask(*targets);
+    #[test]
+    fn mate_scores_keep_their_distance_through_the_cache() {
+        for score in [mated_at(7), -mated_at(7)] {
+            let cached = score_to_cache(score, 3);
+            assert_eq!(score_from_cache(cached, 3), score);
 
-    for mv in movegen {
-        let new_game = game.make_move_new(mv);
-        let new_score = -quiescence_search(&new_game, -beta, -alpha, depth_left - 1);
-        if new_score >= beta {
-            return beta;
+            // Reached two plies later, the mate is two plies further from the root.
+            let later = score_from_cache(cached, 5);
+            assert_eq!(later.abs(), score.abs() - 2);
+            assert_eq!(later.signum(), score.signum());
+            let sooner = score_from_cache(cached, 1);
+            assert_eq!(sooner.abs(), score.abs() + 2);
         }
-        if score > alpha {
-            alpha = score;
+    }
+
+    #[test]
+    fn other_scores_are_cached_unchanged() {
+        for score in [0, 250, -250, MATE_THRESHOLD - 1, -MATE_THRESHOLD + 1] {
+            assert_eq!(score_to_cache(score, 9), score);
+            assert_eq!(score_from_cache(score, 9), score);
         }
     }
-    return alpha;
 }
@@ -0,0 +1,216 @@
+use chess::{
+    get_bishop_moves, get_king_moves, get_knight_moves, get_pawn_attacks, get_rook_moves, BitBoard,
+    Board, ChessMove, Color, Piece, Square, ALL_PIECES, EMPTY,
+};
+
+use crate::weights;
+
+/// Value of a piece in an exchange. The king can never be captured, but it can end an
+/// exchange by recapturing last.
+fn piece_value(piece: Piece) -> i32 {
+    match piece {
+        Piece::Pawn => weights::PAWN_MV.mg,
+        Piece::Knight => weights::KNIGHT_MV.mg,
+        Piece::Bishop => weights::BISHOP_MV.mg,
+        Piece::Rook => weights::ROOK_MV.mg,
+        Piece::Queen => weights::QUEEN_MV.mg,
+        Piece::King => 20 * weights::QUEEN_MV.mg,
+    }
+}
+
+/// All pieces of both colors that attack `square`, with sliding attacks blocked by
+/// `occupied`.
+fn attackers(board: &Board, square: Square, occupied: BitBoard) -> BitBoard {
+    let pawns = board.pieces(Piece::Pawn);
+    let diagonal = board.pieces(Piece::Bishop) | board.pieces(Piece::Queen);
+    let straight = board.pieces(Piece::Rook) | board.pieces(Piece::Queen);
+
+    // A pawn attacks `square` if a pawn of the other color on `square` attacks it.
+    get_pawn_attacks(
+        square,
+        Color::White,
+        pawns & board.color_combined(Color::Black),
+    ) | get_pawn_attacks(
+        square,
+        Color::Black,
+        pawns & board.color_combined(Color::White),
+    ) | (get_knight_moves(square) & board.pieces(Piece::Knight))
+        | (get_bishop_moves(square, occupied) & diagonal)
+        | (get_rook_moves(square, occupied) & straight)
+        | (get_king_moves(square) & board.pieces(Piece::King))
+}
+
+/// The least valuable piece among `attackers`.
+fn least_valuable_attacker(board: &Board, attackers: BitBoard) -> Option<(Square, Piece)> {
+    ALL_PIECES.iter().find_map(|&piece| {
+        let pieces = attackers & board.pieces(piece);
+        (pieces != EMPTY).then(|| (pieces.to_square(), piece))
+    })
+}
+
+/// Static exchange evaluation: the material balance (in cp) for the side to move after
+/// `mv` and the best sequence of recaptures on its destination square.
+///
+/// Both sides recapture with their least valuable attacker and may stop the exchange
+/// at any point. Sliding pieces behind other attackers join in once the pieces in front
+/// of them have captured. Pins are not taken into account.
+pub fn see(board: &Board, mv: ChessMove) -> i32 {
+    let target = mv.get_dest();
+    let source = mv.get_source();
+    let moving_piece = match board.piece_on(source) {
+        Some(piece) => piece,
+        None => return 0,
+    };
+
+    // A pawn moving to an empty square of another file captures en passant.
+    let captured_value = match board.piece_on(target) {
+        Some(piece) => piece_value(piece),
+        None if moving_piece == Piece::Pawn && source.get_file() != target.get_file() => {
+            weights::PAWN_MV.mg
+        }
+        None => 0,
+    };
+
+    let mut gains = [0; 32];
+    let mut depth = 0;
+    gains[0] = captured_value;
+    let mut piece_on_target = moving_piece;
+    if let Some(promotion) = mv.get_promotion() {
+        gains[0] += piece_value(promotion) - weights::PAWN_MV.mg;
+        piece_on_target = promotion;
+    }
+
+    let diagonal = board.pieces(Piece::Bishop) | board.pieces(Piece::Queen);
+    let straight = board.pieces(Piece::Rook) | board.pieces(Piece::Queen);
+    let mut occupied = *board.combined() ^ BitBoard::from_square(source);
+    let mut attackers = attackers(board, target, occupied) & occupied;
+    let mut color = !board.side_to_move();
+
+    while let Some((square, piece)) =
+        least_valuable_attacker(board, attackers & board.color_combined(color))
+    {
+        // The king cannot recapture on a square that is still attacked.
+        if piece == Piece::King && attackers & board.color_combined(!color) != EMPTY {
+            break;
+        }
+
+        depth += 1;
+        gains[depth] = piece_value(piece_on_target) - gains[depth - 1];
+        piece_on_target = piece;
+
+        // Sliding pieces behind the capturing piece attack the square now (x-rays).
+        occupied ^= BitBoard::from_square(square);
+        attackers |= match piece {
+            Piece::Pawn | Piece::Bishop => get_bishop_moves(target, occupied) & diagonal,
+            Piece::Rook => get_rook_moves(target, occupied) & straight,
+            Piece::Queen => {
+                (get_bishop_moves(target, occupied) & diagonal)
+                    | (get_rook_moves(target, occupied) & straight)
+            }
+            Piece::Knight | Piece::King => EMPTY,
+        };
+        attackers &= occupied;
+        color = !color;
+    }
+
+    // Each side only continues the exchange if it does not lose by it.
+    while depth > 0 {
+        gains[depth - 1] = -(-gains[depth - 1]).max(gains[depth]);
+        depth -= 1;
+    }
+    gains[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn see_of(fen: &str, mv: &str) -> i32 {
+        let board = Board::from_str(fen).unwrap();
+        see(&board, ChessMove::from_str(mv).unwrap())
+    }
+
+    #[test]
+    fn undefended_pawn() {
+        assert_eq!(
+            see_of("1k1r4/1pp4p/p7/4p3/8/P5P1/1PP4P/2K1R3 w - - 0 1", "e1e5"),
+            weights::PAWN_MV.mg
+        );
+    }
+
+    #[test]
+    fn defended_pawn_with_x_ray_attackers() {
+        assert_eq!(
+            see_of(
+                "1k1r3q/1ppn3p/p4b2/4p3/8/P2N2P1/1PP1R1BP/2K1Q3 w - - 0 1",
+                "d3e5"
+            ),
+            weights::PAWN_MV.mg - weights::KNIGHT_MV.mg
+        );
+    }
+
+    #[test]
+    fn pawn_defended_by_pawn() {
+        assert_eq!(
+            see_of("4k3/8/3p4/4p3/8/8/8/4RK2 w - - 0 1", "e1e5"),
+            weights::PAWN_MV.mg - weights::ROOK_MV.mg
+        );
+    }
+
+    #[test]
+    fn doubled_rooks_x_ray() {
+        assert_eq!(
+            see_of("4r1k1/4r3/8/8/8/8/4R3/4R1K1 w - - 0 1", "e2e7"),
+            weights::ROOK_MV.mg
+        );
+    }
+
+    #[test]
+    fn queen_behind_bishop_x_ray() {
+        // Bxf6 gxf6 Qxf6 wins knight and pawn for the bishop.
+        assert_eq!(
+            see_of("6k1/6p1/5n2/8/8/2B5/1Q6/6K1 w - - 0 1", "c3f6"),
+            weights::KNIGHT_MV.mg - weights::BISHOP_MV.mg + weights::PAWN_MV.mg
+        );
+    }
+
+    #[test]
+    fn king_cannot_recapture_defended_piece() {
+        assert_eq!(
+            see_of("8/8/8/8/8/1k6/1p6/1R4KR w - - 0 1", "b1b2"),
+            weights::PAWN_MV.mg - weights::ROOK_MV.mg
+        );
+        assert_eq!(
+            see_of("8/8/8/8/8/1k6/1p5Q/1R4K1 w - - 0 1", "b1b2"),
+            weights::PAWN_MV.mg
+        );
+    }
+
+    #[test]
+    fn en_passant() {
+        assert_eq!(
+            see_of("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", "e5d6"),
+            weights::PAWN_MV.mg
+        );
+    }
+
+    #[test]
+    fn promotion() {
+        assert_eq!(
+            see_of("4k3/P7/8/8/8/8/8/4K3 w - - 0 1", "a7a8q"),
+            weights::QUEEN_MV.mg - weights::PAWN_MV.mg
+        );
+        assert_eq!(
+            see_of("r3k3/1P6/8/8/8/8/8/4K3 w - - 0 1", "b7a8q"),
+            weights::ROOK_MV.mg + weights::QUEEN_MV.mg - weights::PAWN_MV.mg
+        );
+    }
+
+    #[test]
+    fn quiet_move_to_attacked_square() {
+        let fen = "4k3/8/8/8/2p5/8/8/2N1K3 w - - 0 1";
+        assert_eq!(see_of(fen, "c1d3"), -weights::KNIGHT_MV.mg);
+        assert_eq!(see_of(fen, "c1e2"), 0);
+    }
+}
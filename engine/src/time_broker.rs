@@ -1,74 +1,392 @@
-use chess::Color;
-use std::time::Duration;
+use chess::{ChessMove, Color};
+use std::{sync::Arc, time::Duration};
 use tokio::{
     sync::watch::{self, Receiver, Sender},
     time::{sleep_until, Instant},
 };
 use vampirc_uci::UciTimeControl;
 
+use crate::search::SearchInfo;
+
+/// Time kept in reserve on every move to absorb GUI and transport latency.
+const MOVE_OVERHEAD: Duration = Duration::from_millis(30);
+/// Smallest budget we ever hand to the search, so it can at least finish depth 1.
+const MIN_MOVE_TIME: Duration = Duration::from_millis(10);
+/// Budget used when the GUI sends `go wtime/btime` without our own clock.
+const FALLBACK_MOVE_TIME: Duration = Duration::from_secs(10);
+/// Number of moves we expect to still play in sudden death and increment games.
+const DEFAULT_MOVES_TO_GO: u32 = 30;
+/// Upper bound for `movestogo`, some GUIs send the full length of the time control.
+const MAX_MOVES_TO_GO: u32 = 50;
+/// How many soft limits the search may use on a single move in the worst case.
+const HARD_LIMIT_FACTOR: u32 = 4;
+/// Soft limit scale added whenever the best move changes between iterations.
+const BEST_MOVE_CHANGE_EXTENSION: f64 = 0.5;
+/// Soft limit scale removed for every iteration that confirms the best move.
+const BEST_MOVE_STABLE_REDUCTION: f64 = 0.1;
+/// Lowest soft limit scale for a best move that has been stable for long.
+const MIN_SOFT_SCALE: f64 = 0.5;
+/// Score drop (in cp) between iterations that is treated as a failing line.
+const SCORE_DROP_THRESHOLD: i32 = 30;
+/// Soft limit scale added when the score drops by the threshold or more.
+const SCORE_DROP_EXTENSION: f64 = 0.5;
+/// Expected ratio between the duration of an iteration and the one before it.
+const ITERATION_GROWTH_FACTOR: u32 = 2;
+
+/// Thinking time for a single move.
+///
+/// The search should not start a new iteration after `soft` has elapsed and must
+/// stop at `hard` at the latest.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TimeLimits {
+    pub soft: Duration,
+    pub hard: Duration,
+}
+
+impl TimeLimits {
+    /// Derive the limits for the side `own_color` from a UCI time control.
+    ///
+    /// Returns `None` for time controls without a limit (`infinite`, `ponder`).
+    pub fn from_time_control(own_color: Color, time_control: &UciTimeControl) -> Option<Self> {
+        match time_control {
+            UciTimeControl::MoveTime(duration) => {
+                let move_time = to_std_duration(duration)
+                    .saturating_sub(MOVE_OVERHEAD)
+                    .max(MIN_MOVE_TIME);
+                Some(TimeLimits {
+                    soft: move_time,
+                    hard: move_time,
+                })
+            }
+            UciTimeControl::TimeLeft {
+                white_time,
+                black_time,
+                white_increment,
+                black_increment,
+                moves_to_go,
+            } => {
+                let (time, increment) = match own_color {
+                    Color::White => (white_time, white_increment),
+                    Color::Black => (black_time, black_increment),
+                };
+                let increment = increment.as_ref().map_or(Duration::ZERO, to_std_duration);
+
+                Some(match time {
+                    Some(time) => {
+                        TimeLimits::allocate(to_std_duration(time), increment, *moves_to_go)
+                    }
+                    None => TimeLimits {
+                        soft: FALLBACK_MOVE_TIME,
+                        hard: FALLBACK_MOVE_TIME,
+                    },
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Split the remaining clock time into a per-move budget.
+    ///
+    /// Every move gets an equal share of the clock over the expected number of
+    /// remaining moves plus most of the increment. The hard limit allows the search
+    /// to overrun that share on difficult moves, but never touches the last quarter
+    /// of the clock.
+    fn allocate(time: Duration, increment: Duration, moves_to_go: Option<u8>) -> Self {
+        let available = time.saturating_sub(MOVE_OVERHEAD);
+        let moves_to_go = moves_to_go
+            .map_or(DEFAULT_MOVES_TO_GO, |m| m as u32)
+            .clamp(1, MAX_MOVES_TO_GO);
+
+        let max_soft = available / 2;
+        let max_hard = available * 3 / 4;
+
+        let soft = (available / moves_to_go + increment * 3 / 4)
+            .min(max_soft)
+            .max(MIN_MOVE_TIME);
+        let hard = (soft * HARD_LIMIT_FACTOR).min(max_hard).max(soft);
+
+        TimeLimits { soft, hard }
+    }
+}
+
+/// Per-search view of the `TimeLimits` that decides between iterations whether the
+/// search should go one ply deeper.
+///
+/// The soft limit is scaled with the stability of the search: changing best moves and
+/// dropping scores buy more time, a best move confirmed by many iterations gives some
+/// back. The hard limit is enforced by the timer of the `TimeBroker`.
+#[derive(Clone, Debug)]
+pub struct TimeManager {
+    start: std::time::Instant,
+    limits: TimeLimits,
+    soft_scale: f64,
+    last_iteration_start: std::time::Instant,
+    last_best_move: Option<ChessMove>,
+    last_score: Option<i32>,
+}
+
+impl TimeManager {
+    pub fn new(limits: TimeLimits) -> TimeManager {
+        let now = std::time::Instant::now();
+        TimeManager {
+            start: now,
+            limits,
+            soft_scale: 1.0,
+            last_iteration_start: now,
+            last_best_move: None,
+            last_score: None,
+        }
+    }
+
+    /// Feed the result of a finished iteration and check if another iteration should
+    /// be started.
+    pub fn should_start_iteration(&mut self, info: &SearchInfo) -> bool {
+        let best_move = info.pv().first().copied();
+
+        if self.last_best_move.is_some() {
+            if best_move != self.last_best_move {
+                self.soft_scale += BEST_MOVE_CHANGE_EXTENSION;
+            } else {
+                self.soft_scale =
+                    (self.soft_scale - BEST_MOVE_STABLE_REDUCTION).max(MIN_SOFT_SCALE);
+            }
+        }
+        if let Some(last_score) = self.last_score {
+            if last_score - info.score() >= SCORE_DROP_THRESHOLD {
+                self.soft_scale += SCORE_DROP_EXTENSION;
+            }
+        }
+        self.last_best_move = best_move;
+        self.last_score = Some(info.score());
+
+        let now = std::time::Instant::now();
+        let elapsed = now - self.start;
+        let last_iteration = now - self.last_iteration_start;
+        self.last_iteration_start = now;
+
+        let soft = self
+            .limits
+            .soft
+            .mul_f64(self.soft_scale)
+            .min(self.limits.hard);
+
+        // An iteration that cannot finish before the hard limit is wasted time.
+        elapsed < soft && elapsed + last_iteration * ITERATION_GROWTH_FACTOR < self.limits.hard
+    }
+}
+
+fn to_std_duration(duration: &vampirc_uci::Duration) -> Duration {
+    // Negative clock times are possible when we are already flagging.
+    duration.to_std().unwrap_or(Duration::ZERO)
+}
+
+/// Receiving ends of the channels a running search is controlled with.
+pub struct SearchSignals {
+    /// Becomes `true` when the search has to stop immediately.
+    pub cancel: Receiver<bool>,
+    /// Updated when the clock for the search starts, which is delayed until
+    /// `ponderhit` for pondering searches.
+    pub time_manager: Receiver<Option<TimeManager>>,
+}
+
 pub struct TimeBroker {
-    cancel_sender: Sender<bool>,
-    cancel_receiver: Receiver<bool>,
-    duration: Option<Duration>,
+    cancel_sender: Arc<Sender<bool>>,
+    time_manager_sender: Sender<Option<TimeManager>>,
+    limits: Option<TimeLimits>,
 }
 
 impl TimeBroker {
     pub fn new() -> TimeBroker {
-        let (cancel_sender, cancel_receiver) = watch::channel(false);
+        let (cancel_sender, _) = watch::channel(false);
+        let (time_manager_sender, _) = watch::channel(None);
         TimeBroker {
-            cancel_sender: cancel_sender,
-            cancel_receiver: cancel_receiver,
-            duration: None,
+            cancel_sender: Arc::new(cancel_sender),
+            time_manager_sender,
+            limits: None,
         }
     }
 
     pub fn seed_time_control(&mut self, own_color: Color, time_control: &UciTimeControl) {
-        self.duration = match time_control {
-            UciTimeControl::MoveTime(duration) => duration.to_std().map_or(None, |d| Some(d)),
-            UciTimeControl::TimeLeft {
-                white_increment,
-                black_increment,
-                ..
-            } => match own_color {
-                Color::White => white_increment.map_or(Some(Duration::from_secs(10)), |d| {
-                    d.to_std()
-                        .map_or(Some(Duration::from_secs(10)), |ds| Some(ds))
-                }),
-                Color::Black => black_increment.map_or(Some(Duration::from_secs(10)), |d| {
-                    d.to_std()
-                        .map_or(Some(Duration::from_secs(10)), |ds| Some(ds))
-                }),
-            },
-            _ => None,
-        };
-        if self.duration == Some(Duration::ZERO) {
-            self.duration = Some(Duration::from_secs(10));
+        self.limits = TimeLimits::from_time_control(own_color, time_control);
+        match self.limits {
+            Some(limits) => println!(
+                "info string Time limits set: soft {:?} hard {:?}",
+                limits.soft, limits.hard
+            ),
+            None => println!("info string Time limits set: none"),
         }
-        println!("info string Duration set: {:?}", self.duration);
     }
 
-    pub fn start_timer(&mut self) -> Option<Receiver<bool>> {
-        if self.duration.is_none() {
-            return None;
+    /// Open new channels for a search, `send_stop` and the clock only affect the
+    /// latest search.
+    pub fn start_search(&mut self) -> SearchSignals {
+        let (cancel_sender, cancel) = watch::channel(false);
+        let (time_manager_sender, time_manager) = watch::channel(None);
+        self.cancel_sender = Arc::new(cancel_sender);
+        self.time_manager_sender = time_manager_sender;
+        self.limits = None;
+        SearchSignals {
+            cancel,
+            time_manager,
         }
-        let (cancel_sender, cancel_receiver) = watch::channel(false);
+    }
 
-        let moved_duration = self.duration.unwrap();
+    /// Start the clock of the latest search: hand it a `TimeManager` for the soft
+    /// limit and enforce the hard limit with a timer.
+    ///
+    /// The search is notified even without a time limit, which is how `ponderhit`
+    /// reaches a pondering search.
+    pub fn start_clock(&self) {
+        let _ = self
+            .time_manager_sender
+            .send(self.limits.map(TimeManager::new));
+
+        let moved_duration = match self.limits {
+            Some(limits) => limits.hard,
+            None => return,
+        };
+        let cancel_sender = self.cancel_sender.clone();
 
         tokio::spawn(async move {
             println!("info string TimerTask started");
-            sleep_until(Instant::now() + moved_duration).await;
-            cancel_sender.send(true).unwrap();
+            tokio::select! {
+                _ = sleep_until(Instant::now() + moved_duration) => {
+                    // The search might have finished on its own in the meantime.
+                    let _ = cancel_sender.send(true);
+                }
+                _ = cancel_sender.closed() => {}
+            }
             println!("info string TimerTask shutdown");
         });
-        Some(cancel_receiver)
     }
 
     pub fn send_stop(&self) {
-        self.cancel_sender.send(true).unwrap();
+        // Fails if no search is running, which is fine.
+        let _ = self.cancel_sender.send(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time_left(
+        white_ms: i64,
+        black_ms: i64,
+        increment_ms: Option<i64>,
+        moves_to_go: Option<u8>,
+    ) -> UciTimeControl {
+        UciTimeControl::TimeLeft {
+            white_time: Some(vampirc_uci::Duration::milliseconds(white_ms)),
+            black_time: Some(vampirc_uci::Duration::milliseconds(black_ms)),
+            white_increment: increment_ms.map(vampirc_uci::Duration::milliseconds),
+            black_increment: increment_ms.map(vampirc_uci::Duration::milliseconds),
+            moves_to_go,
+        }
+    }
+
+    fn limits(own_color: Color, time_control: &UciTimeControl) -> TimeLimits {
+        TimeLimits::from_time_control(own_color, time_control).unwrap()
+    }
+
+    #[test]
+    fn sudden_death() {
+        let available = Duration::from_millis(60_000) - MOVE_OVERHEAD;
+        let soft = available / DEFAULT_MOVES_TO_GO;
+        assert_eq!(
+            limits(Color::White, &time_left(60_000, 1_000, None, None)),
+            TimeLimits {
+                soft,
+                hard: soft * HARD_LIMIT_FACTOR,
+            }
+        );
+    }
+
+    #[test]
+    fn own_clock_is_used() {
+        assert_eq!(
+            limits(Color::Black, &time_left(1_000, 60_000, None, None)),
+            limits(Color::White, &time_left(60_000, 1_000, None, None))
+        );
+    }
+
+    #[test]
+    fn increment() {
+        let available = Duration::from_millis(10_000) - MOVE_OVERHEAD;
+        let soft = available / DEFAULT_MOVES_TO_GO + Duration::from_millis(750);
+        assert_eq!(
+            limits(Color::White, &time_left(10_000, 10_000, Some(1_000), None)),
+            TimeLimits {
+                soft,
+                hard: soft * HARD_LIMIT_FACTOR,
+            }
+        );
+    }
+
+    #[test]
+    fn moves_to_go() {
+        let available = Duration::from_millis(120_000) - MOVE_OVERHEAD;
+        let soft = available / 40;
+        assert_eq!(
+            limits(Color::White, &time_left(120_000, 120_000, None, Some(40))),
+            TimeLimits {
+                soft,
+                hard: soft * HARD_LIMIT_FACTOR,
+            }
+        );
+    }
+
+    #[test]
+    fn last_move_before_time_control_is_capped() {
+        // The whole clock would be the share of the last move, the soft limit is capped
+        // at half and the hard limit at three quarters of the available time.
+        let available = Duration::from_millis(60_000) - MOVE_OVERHEAD;
+        assert_eq!(
+            limits(Color::White, &time_left(60_000, 60_000, None, Some(1))),
+            TimeLimits {
+                soft: available / 2,
+                hard: available * 3 / 4,
+            }
+        );
+    }
+
+    #[test]
+    fn large_increment_is_capped() {
+        let available = Duration::from_millis(1_000) - MOVE_OVERHEAD;
+        let limits = limits(Color::White, &time_left(1_000, 1_000, Some(5_000), None));
+        assert_eq!(limits.soft, available / 2);
+        assert_eq!(limits.hard, available * 3 / 4);
+    }
+
+    #[test]
+    fn almost_flagging() {
+        assert_eq!(
+            limits(Color::White, &time_left(20, 20, None, None)),
+            TimeLimits {
+                soft: MIN_MOVE_TIME,
+                hard: MIN_MOVE_TIME,
+            }
+        );
+    }
+
+    #[test]
+    fn move_time() {
+        let time_control = UciTimeControl::MoveTime(vampirc_uci::Duration::milliseconds(1_000));
+        let move_time = Duration::from_millis(1_000) - MOVE_OVERHEAD;
+        assert_eq!(
+            limits(Color::White, &time_control),
+            TimeLimits {
+                soft: move_time,
+                hard: move_time,
+            }
+        );
     }
 
-    pub fn get_cancel_receiver(&self) -> Receiver<bool> {
-        return self.cancel_receiver.clone();
+    #[test]
+    fn no_limit() {
+        assert_eq!(
+            TimeLimits::from_time_control(Color::White, &UciTimeControl::Infinite),
+            None
+        );
     }
 }
@@ -0,0 +1,352 @@
+use std::{
+    mem,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use chess::{ChessMove, ALL_PIECES, ALL_SQUARES};
+
+#[derive(PartialEq, PartialOrd, Clone, Copy, Debug, Default)]
+pub enum AlphaBetaFlag {
+    Exact,
+    #[default]
+    LowerBound,
+    UpperBound,
+}
+
+/// Unpacked view of a cached search result.
+///
+/// The value has to fit into an `i16`, which holds for all scores up to
+/// `eval::MAX_CP_SCORE`.
+#[derive(PartialEq, PartialOrd, Clone, Copy, Debug, Default)]
+pub struct CacheEntry {
+    pub chess_move: ChessMove,
+    pub depth: usize,
+    pub flag: AlphaBetaFlag,
+    pub value: i32,
+}
+
+// Layout of a packed entry, from the least significant bit:
+// key (16) | move (16) | value (16) | depth (8) | bound (2) | generation (6)
+const KEY_SHIFT: u32 = 0;
+const MOVE_SHIFT: u32 = 16;
+const VALUE_SHIFT: u32 = 32;
+const DEPTH_SHIFT: u32 = 48;
+const BOUND_SHIFT: u32 = 56;
+const GENERATION_SHIFT: u32 = 58;
+
+const GENERATION_MASK: u8 = 0x3F;
+/// Depth equivalent of an entry being one search older, used for replacement.
+const AGE_PENALTY: i32 = 8;
+
+const ENTRIES_PER_BUCKET: usize = 4;
+
+/// Entries sharing an index, sized and aligned to sit in half a cache line.
+#[derive(Default)]
+#[repr(align(32))]
+struct Bucket {
+    entries: [AtomicU64; ENTRIES_PER_BUCKET],
+}
+
+impl Bucket {
+    fn load(&self, slot: usize) -> u64 {
+        self.entries[slot].load(Ordering::Relaxed)
+    }
+}
+
+/// Cache of search results indexed by the Zobrist hash of the position.
+///
+/// Each entry is packed into 64 bits and verified with 16 bits of the hash that are
+/// independent of the bucket index. Moves of an entry can still belong to a colliding
+/// position and have to be checked for legality by the caller.
+///
+/// On a full bucket the entry with the lowest depth is replaced, where every search
+/// an entry has survived costs it `AGE_PENALTY` plies of depth.
+///
+/// Search threads share the table without locking. Entries are read and written as a
+/// single atomic word, so they are never torn; concurrent writes to a bucket can only
+/// make the replacement choice of one of them suboptimal.
+pub struct TranspositionTable {
+    buckets: Vec<Bucket>,
+    generation: u8,
+}
+
+impl TranspositionTable {
+    pub const DEFAULT_SIZE_MB: usize = 64;
+    pub const MAX_SIZE_MB: usize = 65536;
+
+    /// Create a table using about `size_mb` megabytes of memory.
+    pub fn new(size_mb: usize) -> TranspositionTable {
+        let bucket_count = (size_mb.max(1) * 1024 * 1024 / mem::size_of::<Bucket>()).max(1);
+        TranspositionTable {
+            buckets: (0..bucket_count).map(|_| Bucket::default()).collect(),
+            generation: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for bucket in self.buckets.iter_mut() {
+            *bucket = Bucket::default();
+        }
+        self.generation = 0;
+    }
+
+    /// Age all entries, must be called at the start of every search.
+    pub fn new_search(&mut self) {
+        self.generation = (self.generation + 1) & GENERATION_MASK;
+    }
+
+    pub fn get(&self, hash: u64) -> Option<CacheEntry> {
+        let key = key_of(hash);
+        let bucket = &self.buckets[self.index(hash)];
+        (0..ENTRIES_PER_BUCKET)
+            .map(|slot| bucket.load(slot))
+            .find(|&data| is_used(data) && field(data, KEY_SHIFT, 16) as u16 == key)
+            .map(unpack)
+    }
+
+    pub fn add(&self, hash: u64, entry: CacheEntry) {
+        let key = key_of(hash);
+        let generation = self.generation;
+        let bucket = &self.buckets[self.index(hash)];
+
+        let slot = match (0..ENTRIES_PER_BUCKET).find(|&slot| {
+            let data = bucket.load(slot);
+            is_used(data) && field(data, KEY_SHIFT, 16) as u16 == key
+        }) {
+            Some(slot) => {
+                // Keep a deeper result of the same search unless the new one is exact.
+                let old = bucket.load(slot);
+                if entry.flag != AlphaBetaFlag::Exact
+                    && generation_of(old) == generation
+                    && field(old, DEPTH_SHIFT, 8) as usize > entry.depth + 2
+                {
+                    return;
+                }
+                slot
+            }
+            None => (0..ENTRIES_PER_BUCKET)
+                .min_by_key(|&slot| replacement_priority(bucket.load(slot), generation))
+                .unwrap(),
+        };
+
+        bucket.entries[slot].store(pack(key, entry, generation), Ordering::Relaxed);
+    }
+
+    /// Hint the CPU to load the bucket of `hash`, ahead of a `get` in the near future.
+    pub fn prefetch(&self, hash: u64) {
+        #[cfg(target_arch = "x86_64")]
+        unsafe {
+            use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+            let bucket: *const Bucket = &self.buckets[self.index(hash)];
+            _mm_prefetch::<_MM_HINT_T0>(bucket as *const i8);
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        let _ = hash;
+    }
+
+    /// Occupancy of the table by the current search in permille, estimated from the
+    /// first thousand entries.
+    pub fn hashfull(&self) -> u16 {
+        let sample = &self.buckets[..self.buckets.len().min(1000 / ENTRIES_PER_BUCKET)];
+        let used = sample
+            .iter()
+            .flat_map(|bucket| bucket.entries.iter())
+            .map(|entry| entry.load(Ordering::Relaxed))
+            .filter(|&data| is_used(data) && generation_of(data) == self.generation)
+            .count();
+        (used * 1000 / (sample.len() * ENTRIES_PER_BUCKET)) as u16
+    }
+
+    fn index(&self, hash: u64) -> usize {
+        // Maps the hash onto the table without requiring a power of two size. This
+        // uses the high bits of the hash, the key is taken from the low bits.
+        ((hash as u128 * self.buckets.len() as u128) >> 64) as usize
+    }
+}
+
+fn key_of(hash: u64) -> u16 {
+    hash as u16
+}
+
+fn field(data: u64, shift: u32, bits: u32) -> u64 {
+    (data >> shift) & ((1 << bits) - 1)
+}
+
+/// Empty entries are all zero, every stored entry has a bound.
+fn is_used(data: u64) -> bool {
+    field(data, BOUND_SHIFT, 2) != 0
+}
+
+fn generation_of(data: u64) -> u8 {
+    field(data, GENERATION_SHIFT, 6) as u8
+}
+
+/// Lower values are replaced first.
+fn replacement_priority(data: u64, generation: u8) -> i32 {
+    if !is_used(data) {
+        return i32::MIN;
+    }
+    let age = generation.wrapping_sub(generation_of(data)) & GENERATION_MASK;
+    field(data, DEPTH_SHIFT, 8) as i32 - AGE_PENALTY * age as i32
+}
+
+fn pack(key: u16, entry: CacheEntry, generation: u8) -> u64 {
+    let bound: u64 = match entry.flag {
+        AlphaBetaFlag::UpperBound => 1,
+        AlphaBetaFlag::LowerBound => 2,
+        AlphaBetaFlag::Exact => 3,
+    };
+    (key as u64) << KEY_SHIFT
+        | (pack_move(entry.chess_move) as u64) << MOVE_SHIFT
+        | (entry.value as i16 as u16 as u64) << VALUE_SHIFT
+        | (entry.depth.min(u8::MAX as usize) as u64) << DEPTH_SHIFT
+        | bound << BOUND_SHIFT
+        | ((generation & GENERATION_MASK) as u64) << GENERATION_SHIFT
+}
+
+fn unpack(data: u64) -> CacheEntry {
+    CacheEntry {
+        chess_move: unpack_move(field(data, MOVE_SHIFT, 16) as u16),
+        depth: field(data, DEPTH_SHIFT, 8) as usize,
+        flag: match field(data, BOUND_SHIFT, 2) {
+            1 => AlphaBetaFlag::UpperBound,
+            2 => AlphaBetaFlag::LowerBound,
+            _ => AlphaBetaFlag::Exact,
+        },
+        value: field(data, VALUE_SHIFT, 16) as u16 as i16 as i32,
+    }
+}
+
+/// Source (6) | destination (6) | promotion piece index, zero for none (4)
+fn pack_move(mv: ChessMove) -> u16 {
+    let promotion = mv
+        .get_promotion()
+        .map_or(0, |piece| piece.to_index() as u16);
+    mv.get_source().to_index() as u16 | (mv.get_dest().to_index() as u16) << 6 | promotion << 12
+}
+
+fn unpack_move(data: u16) -> ChessMove {
+    let promotion = match data >> 12 {
+        0 => None,
+        index => Some(ALL_PIECES[index as usize]),
+    };
+    ChessMove::new(
+        ALL_SQUARES[(data & 0x3F) as usize],
+        ALL_SQUARES[((data >> 6) & 0x3F) as usize],
+        promotion,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chess::{Piece, Square};
+
+    /// Hashes that only differ in the key share a bucket.
+    fn hash(key: u16) -> u64 {
+        0x1234_5678_0000_0000 | key as u64
+    }
+
+    fn entry(depth: usize, flag: AlphaBetaFlag, value: i32) -> CacheEntry {
+        CacheEntry {
+            chess_move: ChessMove::new(Square::E2, Square::E4, None),
+            depth,
+            flag,
+            value,
+        }
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        for flag in [
+            AlphaBetaFlag::Exact,
+            AlphaBetaFlag::LowerBound,
+            AlphaBetaFlag::UpperBound,
+        ] {
+            for value in [0, 35, -35, 31999, -31999, 31744, -31744] {
+                for depth in [0, 1, 255] {
+                    let entry = entry(depth, flag, value);
+                    let data = pack(0xBEEF, entry, GENERATION_MASK);
+                    assert_eq!(unpack(data), entry);
+                    assert_eq!(field(data, KEY_SHIFT, 16), 0xBEEF);
+                    assert_eq!(generation_of(data), GENERATION_MASK);
+                    assert!(is_used(data));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn depth_is_capped() {
+        let data = pack(1, entry(300, AlphaBetaFlag::Exact, 0), 0);
+        assert_eq!(unpack(data).depth, 255);
+    }
+
+    #[test]
+    fn pack_move_round_trip() {
+        let moves = [
+            ChessMove::new(Square::A1, Square::H8, None),
+            ChessMove::new(Square::H8, Square::A1, None),
+            ChessMove::new(Square::G7, Square::G8, Some(Piece::Knight)),
+            ChessMove::new(Square::B7, Square::A8, Some(Piece::Bishop)),
+            ChessMove::new(Square::B2, Square::B1, Some(Piece::Rook)),
+            ChessMove::new(Square::H2, Square::G1, Some(Piece::Queen)),
+        ];
+        for mv in moves {
+            assert_eq!(unpack_move(pack_move(mv)), mv);
+        }
+    }
+
+    #[test]
+    fn add_and_get() {
+        let table = TranspositionTable::new(1);
+        let stored = entry(7, AlphaBetaFlag::LowerBound, -120);
+        table.add(hash(1), stored);
+        assert_eq!(table.get(hash(1)), Some(stored));
+        assert_eq!(table.get(hash(2)), None);
+    }
+
+    #[test]
+    fn same_position_keeps_deeper_result() {
+        let table = TranspositionTable::new(1);
+        table.add(hash(1), entry(10, AlphaBetaFlag::LowerBound, 50));
+        table.add(hash(1), entry(5, AlphaBetaFlag::UpperBound, 20));
+        assert_eq!(table.get(hash(1)).unwrap().depth, 10);
+
+        // Exact results always replace.
+        table.add(hash(1), entry(5, AlphaBetaFlag::Exact, 20));
+        assert_eq!(table.get(hash(1)).unwrap().depth, 5);
+    }
+
+    #[test]
+    fn full_bucket_replaces_shallowest_entry() {
+        let table = TranspositionTable::new(1);
+        for (key, depth) in [(1, 5), (2, 3), (3, 7), (4, 9)] {
+            table.add(hash(key), entry(depth, AlphaBetaFlag::Exact, 0));
+        }
+        table.add(hash(5), entry(4, AlphaBetaFlag::Exact, 0));
+        assert_eq!(table.get(hash(2)), None);
+        for key in [1, 3, 4, 5] {
+            assert!(table.get(hash(key)).is_some(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn old_entries_are_replaced_first() {
+        let mut table = TranspositionTable::new(1);
+        for key in 1..=4 {
+            table.add(hash(key), entry(10, AlphaBetaFlag::Exact, 0));
+        }
+        table.new_search();
+        // One search costs `AGE_PENALTY` plies, so a depth 10 entry of the last search
+        // ranks below a depth 4 entry of this one.
+        table.add(hash(5), entry(4, AlphaBetaFlag::Exact, 0));
+        table.add(hash(6), entry(3, AlphaBetaFlag::Exact, 0));
+        assert!(table.get(hash(5)).is_some());
+        assert!(table.get(hash(6)).is_some());
+        let old_entries = (1..=4)
+            .filter(|&key| table.get(hash(key)).is_some())
+            .count();
+        assert_eq!(old_entries, 2);
+    }
+}
@@ -1,6 +1,89 @@
-pub const PAWN_MV: i32 = 100;
+use std::ops::{Add, AddAssign, Mul, Neg, Sub};
+
+use serde::{Deserialize, Serialize};
+
+/// Evaluation weight with separate midgame and endgame values, which are blended by
+/// the game phase.
+///
+/// Parameter files store it as the pair `[mg, eg]`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(from = "(i32, i32)", into = "(i32, i32)")]
+pub struct Weight {
+    pub mg: i32,
+    pub eg: i32,
+}
+
+impl Weight {
+    pub const fn new(mg: i32, eg: i32) -> Weight {
+        Weight { mg, eg }
+    }
+}
+
+impl From<(i32, i32)> for Weight {
+    fn from((mg, eg): (i32, i32)) -> Weight {
+        Weight::new(mg, eg)
+    }
+}
+
+impl From<Weight> for (i32, i32) {
+    fn from(weight: Weight) -> (i32, i32) {
+        (weight.mg, weight.eg)
+    }
+}
+
+impl Add for Weight {
+    type Output = Weight;
+
+    fn add(self, other: Weight) -> Weight {
+        Weight::new(self.mg + other.mg, self.eg + other.eg)
+    }
+}
+
+impl AddAssign for Weight {
+    fn add_assign(&mut self, other: Weight) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Weight {
+    type Output = Weight;
+
+    fn sub(self, other: Weight) -> Weight {
+        Weight::new(self.mg - other.mg, self.eg - other.eg)
+    }
+}
+
+impl Neg for Weight {
+    type Output = Weight;
+
+    fn neg(self) -> Weight {
+        Weight::new(-self.mg, -self.eg)
+    }
+}
+
+impl Mul<i32> for Weight {
+    type Output = Weight;
+
+    fn mul(self, factor: i32) -> Weight {
+        Weight::new(self.mg * factor, self.eg * factor)
+    }
+}
+
+/// Combine the midgame and endgame tables of a piece into one table of weights.
+const fn pst(mg: [i32; 64], eg: [i32; 64]) -> [Weight; 64] {
+    let mut table = [Weight::new(0, 0); 64];
+    let mut square = 0;
+    while square < 64 {
+        table[square] = Weight::new(mg[square], eg[square]);
+        square += 1;
+    }
+    table
+}
+
+pub const PAWN_MV: Weight = Weight::new(100, 120);
+pub const PAWN_PST: [Weight; 64] = pst(PAWN_PST_MG, PAWN_PST_EG);
 #[rustfmt::skip]
-pub const PAWN_PST: [i32; 64] = [
+const PAWN_PST_MG: [i32; 64] = [
     0, 0, 0, 0, 0, 0, 0, 0,
     -6, -4, 1, -24, -24, 1, -4, -6,
     -4, -4, 1, 5, 5, 1, -4, -4,
@@ -10,10 +93,22 @@ pub const PAWN_PST: [i32; 64] = [
     -6, -4, 1, 1, 1, 1, -4, -6,
     0, 0, 0, 0, 0, 0, 0, 0,
 ];
+#[rustfmt::skip]
+const PAWN_PST_EG: [i32; 64] = [
+	0, 0, 0, 0, 0, 0, 0, 0,
+	5, 5, 5, 5, 5, 5, 5, 5,
+	5, 5, 5, 5, 5, 5, 5, 5,
+	10, 10, 10, 10, 10, 10, 10, 10,
+	20, 20, 20, 20, 20, 20, 20, 20,
+	35, 35, 35, 35, 35, 35, 35, 35,
+	60, 60, 60, 60, 60, 60, 60, 60,
+	0, 0, 0, 0, 0, 0, 0, 0,
+];
 
-pub const KNIGHT_MV: i32 = 320;
+pub const KNIGHT_MV: Weight = Weight::new(320, 300);
+pub const KNIGHT_PST: [Weight; 64] = pst(KNIGHT_PST_MG, KNIGHT_PST_EG);
 #[rustfmt::skip]
-pub const KNIGHT_PST: [i32; 64] = [
+const KNIGHT_PST_MG: [i32; 64] = [
 	-8, -12, -8, -8, -8, -8, -12, -8,
 	-8, 0, 1, 2, 2, 1, 0, -8,
 	-8, 0, 4, 4, 4, 4, 0, -8,
@@ -23,10 +118,22 @@ pub const KNIGHT_PST: [i32; 64] = [
 	-8, 0, 0, 0, 0, 0, 0, -8,
 	-8, -8, -8, -8, -8, -8, -8, -8,
 ];
+#[rustfmt::skip]
+const KNIGHT_PST_EG: [i32; 64] = [
+	-12, -8, -4, 0, 0, -4, -8, -12,
+	-8, -4, 0, 4, 4, 0, -4, -8,
+	-4, 0, 4, 8, 8, 4, 0, -4,
+	0, 4, 8, 12, 12, 8, 4, 0,
+	0, 4, 8, 12, 12, 8, 4, 0,
+	-4, 0, 4, 8, 8, 4, 0, -4,
+	-8, -4, 0, 4, 4, 0, -4, -8,
+	-12, -8, -4, 0, 0, -4, -8, -12,
+];
 
-pub const BISHOP_MV: i32 = 330;
+pub const BISHOP_MV: Weight = Weight::new(330, 320);
+pub const BISHOP_PST: [Weight; 64] = pst(BISHOP_PST_MG, BISHOP_PST_EG);
 #[rustfmt::skip]
-pub const BISHOP_PST: [i32; 64] = [
+const BISHOP_PST_MG: [i32; 64] = [
 	-4, -4, -12, -4, -4, -12, -4, -4,
 	-4, 2, 1, 1, 1, 1, 2, -4,
 	-4, 1, 2, 4, 4, 2, 1, -4,
@@ -36,10 +143,22 @@ pub const BISHOP_PST: [i32; 64] = [
 	-4, 0, 0, 0, 0, 0, 0, -4,
 	-4, -4, -4, -4, -4, -4, -4, -4,
 ];
+#[rustfmt::skip]
+const BISHOP_PST_EG: [i32; 64] = [
+	-6, -4, -2, 0, 0, -2, -4, -6,
+	-4, -2, 0, 2, 2, 0, -2, -4,
+	-2, 0, 2, 4, 4, 2, 0, -2,
+	0, 2, 4, 6, 6, 4, 2, 0,
+	0, 2, 4, 6, 6, 4, 2, 0,
+	-2, 0, 2, 4, 4, 2, 0, -2,
+	-4, -2, 0, 2, 2, 0, -2, -4,
+	-6, -4, -2, 0, 0, -2, -4, -6,
+];
 
-pub const ROOK_MV: i32 = 500;
+pub const ROOK_MV: Weight = Weight::new(500, 540);
+pub const ROOK_PST: [Weight; 64] = pst(ROOK_PST_MG, ROOK_PST_EG);
 #[rustfmt::skip]
-pub const ROOK_PST: [i32; 64] = [
+const ROOK_PST_MG: [i32; 64] = [
 	0, 0, 0, 2, 2, 0, 0, 0,
 	-5, 0, 0, 0, 0, 0, 0, -5,
 	-5, 0, 0, 0, 0, 0, 0, -5,
@@ -49,10 +168,22 @@ pub const ROOK_PST: [i32; 64] = [
 	20, 20, 20, 20, 20, 20, 20, 20,
 	5, 5, 5, 5, 5, 5, 5, 5,
 ];
+#[rustfmt::skip]
+const ROOK_PST_EG: [i32; 64] = [
+	0, 0, 0, 0, 0, 0, 0, 0,
+	0, 0, 0, 0, 0, 0, 0, 0,
+	0, 0, 0, 0, 0, 0, 0, 0,
+	0, 0, 0, 0, 0, 0, 0, 0,
+	0, 0, 0, 0, 0, 0, 0, 0,
+	0, 0, 0, 0, 0, 0, 0, 0,
+	10, 10, 10, 10, 10, 10, 10, 10,
+	5, 5, 5, 5, 5, 5, 5, 5,
+];
 
-pub const QUEEN_MV: i32 = 900;
+pub const QUEEN_MV: Weight = Weight::new(900, 950);
+pub const QUEEN_PST: [Weight; 64] = pst(QUEEN_PST_MG, QUEEN_PST_EG);
 #[rustfmt::skip]
-pub const QUEEN_PST: [i32; 64] = [
+const QUEEN_PST_MG: [i32; 64] = [
 	-5, -5, -5, -5, -5, -5, -5, -5,
 	0, 0, 1, 1, 1, 1, 0, 0,
 	0, 0, 1, 2, 2, 1, 0, 0,
@@ -62,10 +193,22 @@ pub const QUEEN_PST: [i32; 64] = [
 	0, 0, 1, 1, 1, 1, 0, 0,
 	0, 0, 0, 0, 0, 0, 0, 0,
 ];
+#[rustfmt::skip]
+const QUEEN_PST_EG: [i32; 64] = [
+	-9, -6, -3, 0, 0, -3, -6, -9,
+	-6, -3, 0, 3, 3, 0, -3, -6,
+	-3, 0, 3, 6, 6, 3, 0, -3,
+	0, 3, 6, 9, 9, 6, 3, 0,
+	0, 3, 6, 9, 9, 6, 3, 0,
+	-3, 0, 3, 6, 6, 3, 0, -3,
+	-6, -3, 0, 3, 3, 0, -3, -6,
+	-9, -6, -3, 0, 0, -3, -6, -9,
+];
 
-pub const KING_MV: i32 = 0;
+pub const KING_MV: Weight = Weight::new(0, 0);
+pub const KING_PST: [Weight; 64] = pst(KING_PST_MG, KING_PST_EG);
 #[rustfmt::skip]
-pub const KING_PST: [i32; 64] = [
+const KING_PST_MG: [i32; 64] = [
 	40, 50, 30, 10, 10, 30, 50, 40,
 	30, 40, 20, 0, 0, 20, 40, 30,
 	10, 20, 0, -20, -20, 0, 20, 10,
@@ -76,20 +219,77 @@ pub const KING_PST: [i32; 64] = [
 	-40, -30, -50, -70, -70, -50, -30, -40,
 ];
 #[rustfmt::skip]
-pub const KING_PST_EG: [i32; 64] = [
-	40, 50, 30, 10, 10, 30, 50, 40,
-	30, 40, 20, 0, 0, 20, 40, 30,
-	10, 20, 0, -20, -20, 0, 20, 10,
-	0, 10, -10, -30, -30, -10, 10, 0,
-	-10, 0, -20, -40, -40, -20, 0, -10,
-	-20, -10, -30, -50, -50, -30, -10, -20,
-	-30, -20, -40, -60, -60, -40, -20, -30,
-	-40, -30, -50, -70, -70, -50, -30, -40,
+const KING_PST_EG: [i32; 64] = [
+	-30, -20, -10, 0, 0, -10, -20, -30,
+	-20, -10, 0, 10, 10, 0, -10, -20,
+	-10, 0, 10, 20, 20, 10, 0, -10,
+	0, 10, 20, 30, 30, 20, 10, 0,
+	0, 10, 20, 30, 30, 20, 10, 0,
+	-10, 0, 10, 20, 20, 10, 0, -10,
+	-20, -10, 0, 10, 10, 0, -10, -20,
+	-30, -20, -10, 0, 0, -10, -20, -30,
 ];
 
-pub const PAIR_MOD_BISHOP: i32 = 30;
-pub const PAIR_MOD_KNIGHT: i32 = -8;
-pub const PAIR_MOD_ROOK: i32 = -16;
+pub const PAIR_MOD_BISHOP: Weight = Weight::new(30, 50);
+pub const PAIR_MOD_KNIGHT: Weight = Weight::new(-8, -8);
+pub const PAIR_MOD_ROOK: Weight = Weight::new(-16, -16);
+
+pub const TEMPO_MOD: Weight = Weight::new(20, 10);
+
+/// Mobility by number of safe squares, linear around the typical mobility `center`.
+const fn mobility<const N: usize>(mg: i32, eg: i32, center: i32) -> [Weight; N] {
+    let mut weights = [Weight::new(0, 0); N];
+    let mut squares = 0;
+    while squares < N {
+        let difference = squares as i32 - center;
+        weights[squares] = Weight::new(mg * difference, eg * difference);
+        squares += 1;
+    }
+    weights
+}
+
+pub const KNIGHT_MOBILITY: [Weight; 9] = mobility(4, 4, 4);
+pub const BISHOP_MOBILITY: [Weight; 14] = mobility(5, 5, 6);
+pub const ROOK_MOBILITY: [Weight; 15] = mobility(2, 4, 7);
+pub const QUEEN_MOBILITY: [Weight; 28] = mobility(1, 2, 13);
+
+pub const ROOK_OPEN_FILE: Weight = Weight::new(35, 10);
+pub const ROOK_SEMI_OPEN_FILE: Weight = Weight::new(15, 5);
+pub const ROOK_ON_SEVENTH: Weight = Weight::new(15, 30);
+pub const KNIGHT_OUTPOST: Weight = Weight::new(25, 15);
+pub const BISHOP_OUTPOST: Weight = Weight::new(15, 10);
+/// Per own pawn on the squares of the color of the bishop.
+pub const BAD_BISHOP_PAWN: Weight = Weight::new(-3, -6);
+/// Bishop on a7 or h7 shut in by a pawn on b6 or g6.
+pub const TRAPPED_BISHOP: Weight = Weight::new(-100, -80);
+/// Rook boxed in on the back rank by its uncastled king.
+pub const TRAPPED_ROOK: Weight = Weight::new(-40, -10);
+
+#[rustfmt::skip]
+pub const PASSED_PAWN: [Weight; 8] = [
+    Weight::new(0, 0), Weight::new(5, 10), Weight::new(5, 15), Weight::new(10, 25),
+    Weight::new(20, 45), Weight::new(35, 75), Weight::new(60, 120), Weight::new(0, 0),
+];
+pub const PASSED_PAWN_BLOCKED: Weight = Weight::new(-5, -15);
+pub const PASSED_PAWN_ENEMY_KING_DISTANCE: Weight = Weight::new(0, 5);
+pub const PASSED_PAWN_OWN_KING_DISTANCE: Weight = Weight::new(0, -2);
+pub const DOUBLED_PAWN: Weight = Weight::new(-10, -20);
+pub const ISOLATED_PAWN: Weight = Weight::new(-10, -15);
+pub const BACKWARD_PAWN: Weight = Weight::new(-8, -10);
+pub const CONNECTED_PAWN: Weight = Weight::new(8, 10);
+pub const PAWN_ISLAND: Weight = Weight::new(-5, -10);
 
-pub const MOBILITY_MOD: i32 = 1;
-pub const TEMPO_MOD: i32 = 20;
+/// Attack units per attacked square of the king zone, by attacking piece.
+pub const KING_ZONE_ATTACK: [i32; 6] = [0, 2, 2, 3, 5, 0];
+/// Attack units for a safe check available to the attacking piece.
+pub const KING_SAFE_CHECK: [i32; 6] = [0, 6, 4, 8, 8, 0];
+/// Weight of the squared attack units.
+pub const KING_ATTACK_DANGER: Weight = Weight::new(-1, 0);
+/// Own pawns one, two and three ranks in front of the king.
+pub const KING_PAWN_SHIELD: [Weight; 3] =
+    [Weight::new(12, 0), Weight::new(6, 0), Weight::new(2, 0)];
+/// Enemy pawns one, two and three ranks in front of the king.
+pub const KING_PAWN_STORM: [Weight; 3] =
+    [Weight::new(-5, 0), Weight::new(-12, 0), Weight::new(-8, 0)];
+pub const KING_OPEN_FILE: Weight = Weight::new(-25, 0);
+pub const KING_SEMI_OPEN_FILE: Weight = Weight::new(-12, 0);
@@ -0,0 +1,429 @@
+//! Texel tuning of the evaluation weights.
+//!
+//! Reads quiet positions labelled with the result of the game they were taken from and
+//! adjusts the used weights of `EvalParams` by local search, so the evaluation predicts the
+//! results as well as possible. The win probability of an evaluation is estimated with
+//! the sigmoid `1 / (1 + 10^(-k * eval / 400))`, the scaling constant `k` is fitted to
+//! the start parameters first.
+//!
+//! Each line of the data file holds a FEN followed by the result from the view of
+//! white, as `1-0`, `0-1`, `1/2-1/2` or as a number `1.0`, `0.5`, `0.0`, optionally
+//! quoted or in brackets: `<fen> [0.5]` and EPD lines like `<fen> c9 "1/2-1/2";` both
+//! work. The move counters of the FEN are optional.
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::time::Instant;
+
+use chess::{Board, Color};
+use engine::{EvalParams, PawnHashTable};
+use serde_json::Value;
+
+/// The format of the output file is chosen by its extension, `.json` or `.toml`.
+const USAGE: &str = "Usage: tuner DATA OUTPUT [--params FILE] [--passes N] [--threads N]";
+/// Each pass tries to change every weight once.
+const DEFAULT_PASSES: usize = 100;
+/// Change of a weight per try, in cp.
+const STEP: i32 = 1;
+/// Smallest change of the scaling constant tried.
+const SCALING_PRECISION: f64 = 0.001;
+
+struct Position {
+    board: Board,
+    /// Result of the game from the view of white, 1 for a win.
+    result: f64,
+}
+
+struct Args {
+    data: String,
+    output: String,
+    /// Start parameters, the defaults if not given.
+    params: Option<String>,
+    passes: usize,
+    threads: usize,
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            println!("{} - {}", USAGE, e);
+            return;
+        }
+    };
+
+    let positions = match load_positions(Path::new(&args.data)) {
+        Ok(positions) if !positions.is_empty() => positions,
+        Ok(_) => {
+            println!("No labelled positions in {}", args.data);
+            return;
+        }
+        Err(e) => {
+            println!("Reading {} failed - {}", args.data, e);
+            return;
+        }
+    };
+    let params = match &args.params {
+        Some(path) => match EvalParams::load(Path::new(path)) {
+            Ok(params) => params,
+            Err(e) => {
+                println!("Loading {} failed - {}", path, e);
+                return;
+            }
+        },
+        None => EvalParams::default(),
+    };
+
+    let scores = evaluate_all(&positions, &params, args.threads);
+    let scaling = fit_scaling(&positions, &scores);
+    println!(
+        "{} positions, scaling constant {:.3}, error {:.6}",
+        positions.len(),
+        scaling,
+        mean_squared_error(&positions, &scores, scaling)
+    );
+
+    local_search(&positions, params, scaling, &args);
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut flags = std::env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut params = None;
+    let mut passes = DEFAULT_PASSES;
+    let mut threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--params" | "--passes" | "--threads" => {
+                let value = match flags.next() {
+                    Some(value) => value,
+                    None => return Err(format!("missing value for {}", flag)),
+                };
+                match flag.as_str() {
+                    "--params" => params = Some(value),
+                    _ => {
+                        let number = value
+                            .parse::<usize>()
+                            .map_err(|_| format!("invalid value for {} - {}", flag, value))?;
+                        if flag == "--passes" {
+                            passes = number;
+                        } else {
+                            threads = number.max(1);
+                        }
+                    }
+                }
+            }
+            _ if flag.starts_with("--") => return Err(format!("unknown flag {}", flag)),
+            _ => positional.push(flag),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    match (positional.next(), positional.next(), positional.next()) {
+        (Some(data), Some(output), None) => Ok(Args {
+            data,
+            output,
+            params,
+            passes,
+            threads,
+        }),
+        _ => Err("expected a data and an output file".to_string()),
+    }
+}
+
+/// Read all labelled positions of the data file, lines that cannot be parsed are
+/// skipped.
+fn load_positions(path: &Path) -> std::io::Result<Vec<Position>> {
+    let content = fs::read_to_string(path)?;
+    let mut positions = Vec::new();
+    let mut skipped = 0;
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        match parse_position(line) {
+            Some(position) => positions.push(position),
+            None => skipped += 1,
+        }
+    }
+    if skipped > 0 {
+        println!("Skipped {} lines without a position and result", skipped);
+    }
+    Ok(positions)
+}
+
+fn parse_position(line: &str) -> Option<Position> {
+    let tokens = line.split_whitespace().collect::<Vec<_>>();
+    if tokens.len() < 5 {
+        return None;
+    }
+    let result = match tokens[tokens.len() - 1].trim_matches(|c| matches!(c, '"' | '[' | ']' | ';'))
+    {
+        "1-0" | "1.0" | "1" => 1.0,
+        "0-1" | "0.0" | "0" => 0.0,
+        "1/2-1/2" | "0.5" => 0.5,
+        _ => return None,
+    };
+    // Board, side to move, castling rights and en passant square, the move counters do
+    // not matter for the evaluation.
+    let fen = format!("{} 0 1", tokens[..4].join(" "));
+    let board = Board::from_str(&fen).ok()?;
+    Some(Position { board, result })
+}
+
+/// Evaluation of every position from the view of white, split over `threads` threads.
+fn evaluate_all(positions: &[Position], params: &EvalParams, threads: usize) -> Vec<i32> {
+    let chunk_size = positions.len().div_ceil(threads).max(1);
+    thread::scope(|scope| {
+        let handles = positions
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    let mut pawn_table = PawnHashTable::new();
+                    chunk
+                        .iter()
+                        .map(|position| {
+                            let score =
+                                engine::evaluate_position(&position.board, params, &mut pawn_table);
+                            match position.board.side_to_move() {
+                                Color::White => score,
+                                Color::Black => -score,
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+/// Expected result for white of a position evaluated with `score`.
+fn sigmoid(score: i32, scaling: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf(-scaling * score as f64 / 400.0))
+}
+
+fn mean_squared_error(positions: &[Position], scores: &[i32], scaling: f64) -> f64 {
+    let sum = positions
+        .iter()
+        .zip(scores)
+        .map(|(position, &score)| (position.result - sigmoid(score, scaling)).powi(2))
+        .sum::<f64>();
+    sum / positions.len() as f64
+}
+
+/// The scaling constant that best maps the evaluations to the results.
+fn fit_scaling(positions: &[Position], scores: &[i32]) -> f64 {
+    let mut scaling = 1.0;
+    let mut best_error = mean_squared_error(positions, scores, scaling);
+    let mut step = 0.1;
+    while step >= SCALING_PRECISION {
+        let mut improved = true;
+        while improved {
+            improved = false;
+            for candidate in [scaling - step, scaling + step] {
+                let error = mean_squared_error(positions, scores, candidate);
+                if candidate > 0.0 && error < best_error {
+                    scaling = candidate;
+                    best_error = error;
+                    improved = true;
+                    break;
+                }
+            }
+        }
+        step /= 10.0;
+    }
+    scaling
+}
+
+/// Change every weight by `STEP` in both directions and keep changes that lower the
+/// error, until a pass brings no improvement. The parameters are written to the output
+/// file after every pass, so an interrupted run keeps its progress.
+fn local_search(positions: &[Position], params: EvalParams, scaling: f64, args: &Args) {
+    let template = serde_json::to_value(&params).unwrap();
+    let mut values = Vec::new();
+    flatten(&template, &mut values);
+    let fields = value_fields(&template);
+    let tunable = (0..values.len())
+        .filter(|&index| is_tunable(fields[index].0, fields[index].1))
+        .collect::<Vec<_>>();
+    let error_of = |values: &[i32]| {
+        let params = unflatten(&template, values);
+        let scores = evaluate_all(positions, &params, args.threads);
+        mean_squared_error(positions, &scores, scaling)
+    };
+    let mut best_error = error_of(&values);
+
+    for pass in 1..=args.passes {
+        let time = Instant::now();
+        let mut changed = 0;
+        for &index in &tunable {
+            // The engine does not load weights outside of the bounds.
+            let bound = EvalParams::bound(fields[index].0);
+            let current = values[index];
+            let mut improved = false;
+            for step in [STEP, -STEP] {
+                values[index] = (current + step).clamp(-bound, bound);
+                if values[index] == current {
+                    continue;
+                }
+                let error = error_of(&values);
+                if error < best_error {
+                    best_error = error;
+                    improved = true;
+                    break;
+                }
+            }
+            if improved {
+                changed += 1;
+            } else {
+                // Neither direction helped, restore the weight.
+                values[index] = current;
+            }
+        }
+
+        let params = unflatten(&template, &values);
+        if let Err(e) = params.save(Path::new(&args.output)) {
+            println!("Saving {} failed - {}", args.output, e);
+            return;
+        }
+        println!(
+            "Pass {} changed {} of {} weights, error {:.6}, {:.1}s",
+            pass,
+            changed,
+            tunable.len(),
+            best_error,
+            time.elapsed().as_secs_f64()
+        );
+        if changed == 0 {
+            break;
+        }
+    }
+}
+
+/// All integers of the serialized parameters, in the order they are serialized.
+fn flatten(value: &Value, values: &mut Vec<i32>) {
+    match value {
+        Value::Number(number) => values.push(number.as_i64().unwrap() as i32),
+        Value::Array(array) => array.iter().for_each(|value| flatten(value, values)),
+        Value::Object(object) => object.values().for_each(|value| flatten(value, values)),
+        _ => {}
+    }
+}
+
+/// For every integer of `flatten`, the name of its field of `EvalParams` and the index
+/// of the array element it belongs to.
+fn value_fields(template: &Value) -> Vec<(&str, usize)> {
+    let mut value_fields = Vec::new();
+    let fields = match template {
+        Value::Object(fields) => fields,
+        _ => return value_fields,
+    };
+    for (name, value) in fields {
+        let elements = match value {
+            Value::Array(array) => array.iter().collect::<Vec<_>>(),
+            _ => vec![value],
+        };
+        for (element, value) in elements.into_iter().enumerate() {
+            let mut values = Vec::new();
+            flatten(value, &mut values);
+            value_fields.extend(values.iter().map(|_| (name.as_str(), element)));
+        }
+    }
+    value_fields
+}
+
+/// Whether the element of the field `name` of `EvalParams` is used by the evaluation
+/// in a way that does not cancel out.
+fn is_tunable(name: &str, element: usize) -> bool {
+    match name {
+        // Both sides always have one king.
+        "king_mv" => false,
+        // Pawns never stand on the first or last rank.
+        "pawn_pst" => (8..56).contains(&element),
+        "passed_pawn" => (1..7).contains(&element),
+        // Indexed by piece, pawns and kings are not counted as attackers.
+        "king_zone_attack" | "king_safe_check" => (1..5).contains(&element),
+        _ => true,
+    }
+}
+
+/// Parameters with the integers of `template` replaced by `values`, the inverse of
+/// `flatten`.
+fn unflatten(template: &Value, values: &[i32]) -> EvalParams {
+    fn replace(value: &mut Value, values: &mut std::slice::Iter<i32>) {
+        match value {
+            Value::Number(number) => *number = (*values.next().unwrap()).into(),
+            Value::Array(array) => array.iter_mut().for_each(|value| replace(value, values)),
+            Value::Object(object) => object.values_mut().for_each(|value| replace(value, values)),
+            _ => {}
+        }
+    }
+
+    let mut value = template.clone();
+    replace(&mut value, &mut values.iter());
+    serde_json::from_value(value).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unflatten_inverts_flatten() {
+        let mut params = EvalParams::default();
+        params.knight_pst[17].eg = 42;
+        params.king_safe_check[2] = 7;
+        let template = serde_json::to_value(EvalParams::default()).unwrap();
+        let mut values = Vec::new();
+        flatten(&serde_json::to_value(&params).unwrap(), &mut values);
+        assert_eq!(unflatten(&template, &values), params);
+    }
+
+    #[test]
+    fn unused_weights_are_not_tunable() {
+        let template = serde_json::to_value(EvalParams::default()).unwrap();
+        let mut values = Vec::new();
+        flatten(&template, &mut values);
+        let fields = value_fields(&template);
+        assert_eq!(fields.len(), values.len());
+
+        // Mark every integer with its position, so `unflatten` shows where it ended up.
+        let positions = (0..values.len() as i32).collect::<Vec<_>>();
+        let params = unflatten(&template, &positions);
+        let is_tunable = |position: i32| {
+            let (name, element) = fields[position as usize];
+            is_tunable(name, element)
+        };
+        assert!(!is_tunable(params.king_mv.mg) && !is_tunable(params.king_mv.eg));
+        assert!(!is_tunable(params.pawn_pst[0].mg) && is_tunable(params.pawn_pst[8].mg));
+        assert!(!is_tunable(params.passed_pawn[7].eg) && is_tunable(params.passed_pawn[6].eg));
+        assert!(!is_tunable(params.king_zone_attack[0]) && is_tunable(params.king_zone_attack[4]));
+        assert!(!is_tunable(params.king_safe_check[5]) && is_tunable(params.king_safe_check[1]));
+        assert!(is_tunable(params.queen_mv.mg) && is_tunable(params.tempo_mod.eg));
+
+        // Tried values are clamped to the bound of their field.
+        let bound = |position: i32| EvalParams::bound(fields[position as usize].0);
+        assert_eq!(bound(params.king_attack_danger.eg), 16);
+        assert_eq!(bound(params.king_safe_check[2]), 100);
+        assert_eq!(bound(params.queen_pst[10].mg), 4000);
+    }
+
+    #[test]
+    fn parse_results() {
+        let fen = "4k3/8/8/8/8/8/4P3/4K3 w - -";
+        let result = |suffix: &str| {
+            parse_position(&format!("{} {}", fen, suffix)).map(|position| position.result)
+        };
+        assert_eq!(result("1-0"), Some(1.0));
+        assert_eq!(result("0-1"), Some(0.0));
+        assert_eq!(result("[0.5]"), Some(0.5));
+        assert_eq!(result("0 1 [1.0]"), Some(1.0));
+        assert_eq!(result("c9 \"1/2-1/2\";"), Some(0.5));
+        assert_eq!(result("*"), None);
+        assert!(parse_position("1-0").is_none());
+    }
+}